@@ -0,0 +1,66 @@
+// Measures the cost of MerkleTree::insert_leaf for increasing tree depths.
+// Run with: cargo bench --bench insert_leaf
+//
+// Every insert only rehashes the path from the new leaf to the root, so the number of hash calls
+// per insert is depth - 1 and the time per insert grows linearly in the depth (logarithmically in the capacity).
+// MerkleTree::insert_leaves rehashes shared nodes once, so a batch costs about one hash per leaf.
+
+use ark_bn254::Fr;
+use incremental_merkle_tree::hasher::Hasher;
+use incremental_merkle_tree::incremental_merkle_tree::MerkleTree;
+use incremental_merkle_tree::poseidon::PoseidonAlgorithm;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+const INSERTS: usize = 1000;
+
+static HASH_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+// Poseidon2 hasher that counts how many times it is called.
+#[derive(Clone, Debug)]
+struct CountingHasher {}
+
+impl Hasher for CountingHasher {
+    type Hash = Fr;
+
+    fn hash<const N: usize>(data: [Fr; N]) -> Fr {
+        HASH_CALLS.fetch_add(1, Ordering::Relaxed);
+        PoseidonAlgorithm::hash(data)
+    }
+}
+
+fn main() {
+    println!(
+        "{:>6} {:>12} {:>16} {:>14} {:>16} {:>14}",
+        "depth", "capacity", "hashes/insert", "us/insert", "batch hashes", "batch us"
+    );
+    for depth in [11u32, 14, 17, 20] {
+        let mut tree = MerkleTree::<CountingHasher>::build_empty_tree(depth).unwrap();
+
+        HASH_CALLS.store(0, Ordering::Relaxed);
+        let start = Instant::now();
+        for leaf in 0..INSERTS {
+            tree.insert_leaf(Fr::from(leaf as u64)).unwrap();
+        }
+        let elapsed = start.elapsed();
+        let hashes = HASH_CALLS.load(Ordering::Relaxed);
+
+        let mut batched = MerkleTree::<CountingHasher>::build_empty_tree(depth).unwrap();
+        HASH_CALLS.store(0, Ordering::Relaxed);
+        let start = Instant::now();
+        batched.insert_leaves_iter((0..INSERTS).map(|leaf| Fr::from(leaf as u64))).unwrap();
+        let batch_elapsed = start.elapsed();
+        let batch_hashes = HASH_CALLS.load(Ordering::Relaxed);
+        assert_eq!(batched.root(), tree.root());
+
+        println!(
+            "{:>6} {:>12} {:>16} {:>14.2} {:>16.2} {:>14.2}",
+            depth,
+            1usize << (depth - 1),
+            hashes / INSERTS,
+            elapsed.as_secs_f64() * 1e6 / INSERTS as f64,
+            batch_hashes as f64 / INSERTS as f64,
+            batch_elapsed.as_secs_f64() * 1e6 / INSERTS as f64
+        );
+    }
+}
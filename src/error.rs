@@ -0,0 +1,93 @@
+use core::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MerkleTreeError {
+    // The tree has no levels, e.g. it was created with MerkleTree::new() instead of build_empty_tree.
+    EmptyTree,
+    // Every leaf slot is already used.
+    TreeFull { capacity: usize },
+    // The index does not address a leaf of the tree.
+    IndexOutOfRange { index: usize, capacity: usize },
+    // The index addresses a slot in which no leaf has been inserted yet.
+    LeafNotFound { index: usize, leaves_count: usize },
+    // The leaf at this index was removed with MerkleTree::remove_leaf.
+    LeafRemoved(usize),
+    // The nodes needed to open this leaf were discarded by a PrunedMerkleTree.
+    LeafPruned(usize),
+    // The leaves given to MerkleFrontier::to_merkle_tree do not hash to its root.
+    RootMismatch,
+    // The value is already a leaf of the IndexedMerkleTree, at this index.
+    ValueExists { index: usize },
+    // A past tree size (number of leaves) must be between 1 and max.
+    InvalidTreeSize { size: usize, max: usize },
+    // The opening does not have one node per level below the root.
+    InvalidProofLength { expected: usize, found: usize },
+    // No checkpoint with this id is retained.
+    UnknownCheckpoint(u64),
+    // Checkpoint ids must be strictly increasing.
+    CheckpointOutOfOrder { id: u64, last: u64 },
+    // The tree keeps no checkpoints (with_max_checkpoints(0)).
+    CheckpointsDisabled,
+    // Depth 0, or too deep to address the leaves with a usize or to allocate the levels of the tree.
+    InvalidDepth(u32),
+}
+
+impl fmt::Display for MerkleTreeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MerkleTreeError::EmptyTree => write!(f, "There are no leaves in the tree!"),
+            MerkleTreeError::TreeFull { capacity } => write!(f, "The tree is full! (capacity {})", capacity),
+            MerkleTreeError::IndexOutOfRange { index, capacity } => {
+                write!(f, "Leaf index {} is out of range for a tree of {} leaves", index, capacity)
+            }
+            MerkleTreeError::LeafNotFound { index, leaves_count } => {
+                write!(f, "No leaf at index {}, only {} leaves were inserted", index, leaves_count)
+            }
+            MerkleTreeError::LeafRemoved(index) => write!(f, "The leaf at index {} was removed", index),
+            MerkleTreeError::LeafPruned(index) => write!(f, "The path of leaf {} was pruned", index),
+            MerkleTreeError::RootMismatch => write!(f, "The leaves do not match the root of the frontier"),
+            MerkleTreeError::ValueExists { index } => write!(f, "The value is already in the tree at index {}", index),
+            MerkleTreeError::InvalidTreeSize { size, max } => {
+                write!(f, "Invalid tree size {}, expected between 1 and {}", size, max)
+            }
+            MerkleTreeError::InvalidProofLength { expected, found } => {
+                write!(f, "Expected an opening of {} nodes but found {}", expected, found)
+            }
+            MerkleTreeError::UnknownCheckpoint(id) => write!(f, "Unknown checkpoint {}", id),
+            MerkleTreeError::CheckpointOutOfOrder { id, last } => {
+                write!(f, "Checkpoint {} is not after the last checkpoint {}", id, last)
+            }
+            MerkleTreeError::CheckpointsDisabled => write!(f, "Checkpoints are disabled for this tree"),
+            MerkleTreeError::InvalidDepth(depth) => write!(f, "Invalid tree depth {}", depth),
+        }
+    }
+}
+
+impl std::error::Error for MerkleTreeError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Poseidon2Error {
+    // The number of inputs does not match the specified length.
+    InputLengthMismatch { expected: usize, found: usize },
+    // The permutation takes exactly t values.
+    InvalidStateWidth { expected: u32, found: u32 },
+    InvalidConfig(String),
+}
+
+impl fmt::Display for Poseidon2Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Poseidon2Error::InputLengthMismatch { expected, found } => write!(
+                f,
+                "the number of inputs does not match specified length. {} != {}",
+                found, expected
+            ),
+            Poseidon2Error::InvalidStateWidth { expected, found } => {
+                write!(f, "Expected {} values but encountered {}", expected, found)
+            }
+            Poseidon2Error::InvalidConfig(reason) => write!(f, "Invalid Poseidon2 config: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for Poseidon2Error {}
@@ -0,0 +1,225 @@
+use crate::hasher::Hasher;
+use crate::error::MerkleTreeError;
+use crate::incremental_merkle_tree::{check_depth, empty_hashes, MerkleTree};
+use crate::incremental_witness::IncrementalWitness;
+use crate::poseidon::PoseidonAlgorithm;
+
+// Append-only Merkle tree that only keeps its right frontier ("filled subtrees", as in Tornado Cash and Semaphore).
+// For every level below the root it stores the last left node, so inserting a leaf and computing the root
+// take O(depth) time and memory regardless of the number of leaves. Its roots are those of a MerkleTree of the
+// same depth holding the same leaves, but it cannot produce openings: see to_merkle_tree.
+#[derive(Debug, Clone)]
+pub struct MerkleFrontier<H = PoseidonAlgorithm>
+where
+    H: Hasher,
+{
+    depth: u32,
+    filled_subtrees: Vec<H::Hash>,
+    empty_hashes: Vec<H::Hash>,
+    root: H::Hash,
+    // The last inserted leaf, needed to witness it (see witness).
+    last_leaf: Option<H::Hash>,
+    leaves_count: usize,
+}
+
+impl<H> MerkleFrontier<H>
+where
+    H: Hasher,
+{
+    // Frontier of an empty tree of `depth` levels, counting the root.
+    pub fn new(depth: u32) -> Result<Self, MerkleTreeError> {
+        check_depth(depth)?;
+        let empty_hashes = empty_hashes::<H>(depth);
+        Ok(Self {
+            depth,
+            filled_subtrees: empty_hashes[..empty_hashes.len() - 1].to_vec(),
+            root: empty_hashes[empty_hashes.len() - 1],
+            empty_hashes,
+            last_leaf: None,
+            leaves_count: 0,
+        })
+    }
+
+    // Frontier of the tree as it is now, e.g. to keep appending to it with O(depth) memory.
+    pub fn from_tree(tree: &MerkleTree<H>) -> Result<Self, MerkleTreeError> {
+        let mut frontier = Self::new(tree.depth() as u32)?;
+        if tree.leaves_count() > 0 {
+            let last_leaf = tree.leaves_count() - 1;
+            for (level, filled_subtree) in frontier.filled_subtrees.iter_mut().enumerate() {
+                // The last left node of the level is the one above the last leaf, or its left sibling
+                let index = (last_leaf >> level) & !1;
+                *filled_subtree = tree.tree[level][index];
+            }
+            frontier.last_leaf = Some(tree.tree[0][last_leaf]);
+        }
+        frontier.root = *tree.root()?;
+        frontier.leaves_count = tree.leaves_count();
+        Ok(frontier)
+    }
+
+    // Inserts the leaf in the first unused slot and returns its index.
+    pub fn insert_leaf(&mut self, leaf: H::Hash) -> Result<usize, MerkleTreeError> {
+        self.insert_leaf_with(leaf, |_, _| {})
+    }
+
+    // insert_leaf that also passes each node on the path of the new leaf (level, node) to on_path, from the leaf up.
+    pub(crate) fn insert_leaf_with<F>(&mut self, leaf: H::Hash, mut on_path: F) -> Result<usize, MerkleTreeError>
+    where
+        F: FnMut(usize, &H::Hash),
+    {
+        if self.leaves_count >= self.capacity() {
+            return Err(MerkleTreeError::TreeFull { capacity: self.capacity() });
+        }
+
+        let mut current_index = self.leaves_count;
+        let mut current = leaf;
+        for level in 0..self.filled_subtrees.len() {
+            on_path(level, &current);
+            if current_index.is_multiple_of(2) {
+                // Left child: remember it and pair it with the empty right sibling.
+                self.filled_subtrees[level] = current;
+                current = H::concat_and_hash(&current, &self.empty_hashes[level]);
+            } else {
+                // Right child: the left sibling is the last filled subtree of this level.
+                current = H::concat_and_hash(&self.filled_subtrees[level], &current);
+            }
+            current_index /= 2;
+        }
+
+        self.root = current;
+        self.last_leaf = Some(leaf);
+        self.leaves_count += 1;
+        Ok(self.leaves_count - 1)
+    }
+
+    pub fn root(&self) -> &H::Hash {
+        &self.root
+    }
+
+    pub fn leaves_count(&self) -> usize {
+        self.leaves_count
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth as usize
+    }
+
+    // Number of leaves that can be inserted before TreeFull.
+    pub fn capacity(&self) -> usize {
+        1usize << (self.depth - 1)
+    }
+
+    // Witness of the last inserted leaf, which can then follow the next insertions (see IncrementalWitness).
+    // The frontier only knows the path of its last leaf, so older leaves must be witnessed from a MerkleTree.
+    pub fn witness(&self) -> Result<IncrementalWitness<H>, MerkleTreeError> {
+        let leaf = self.last_leaf.ok_or(MerkleTreeError::LeafNotFound { index: 0, leaves_count: 0 })?;
+        let leaf_index = self.leaves_count - 1;
+        let siblings = (0..self.filled_subtrees.len())
+            .map(|level| {
+                if (leaf_index >> level).is_multiple_of(2) {
+                    self.empty_hashes[level]
+                } else {
+                    self.filled_subtrees[level]
+                }
+            })
+            .collect();
+        Ok(IncrementalWitness::new(leaf_index, leaf, siblings, self.clone()))
+    }
+
+    // Builds the full MerkleTree from the inserted leaves, e.g. when openings are needed.
+    // The frontier does not keep the leaves, so they must be provided in insertion order. Returns LeafNotFound if
+    // there are more or fewer leaves than were inserted, and RootMismatch if they do not hash to the root.
+    pub fn to_merkle_tree(&self, leaves: &[H::Hash]) -> Result<MerkleTree<H>, MerkleTreeError> {
+        if leaves.len() != self.leaves_count {
+            let index = leaves.len().min(self.leaves_count);
+            return Err(MerkleTreeError::LeafNotFound { index, leaves_count: index });
+        }
+        let mut tree = MerkleTree::build_empty_tree(self.depth)?;
+        let (_, root) = tree.insert_leaves(leaves)?;
+        if root != self.root {
+            return Err(MerkleTreeError::RootMismatch);
+        }
+        Ok(tree)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ark_bn254::Fr;
+
+    #[test]
+    fn test_frontier_matches_merkle_tree() {
+        let mut frontier = MerkleFrontier::<PoseidonAlgorithm>::new(5).unwrap();
+        let mut merkle_tree = MerkleTree::<PoseidonAlgorithm>::build_empty_tree(5).unwrap();
+        assert_eq!(Ok(frontier.root()), merkle_tree.root());
+
+        for leaf in 0..16u64 {
+            assert_eq!(frontier.insert_leaf(Fr::from(leaf)), merkle_tree.insert_leaf(Fr::from(leaf)));
+            assert_eq!(Ok(frontier.root()), merkle_tree.root());
+        }
+        assert_eq!(frontier.leaves_count(), 16);
+    }
+
+    #[test]
+    fn test_frontier_full() {
+        let mut frontier = MerkleFrontier::<PoseidonAlgorithm>::new(2).unwrap();
+        assert_eq!(frontier.insert_leaf(Fr::from(0u64)), Ok(0));
+        assert_eq!(frontier.insert_leaf(Fr::from(1u64)), Ok(1));
+        assert_eq!(frontier.insert_leaf(Fr::from(2u64)), Err(MerkleTreeError::TreeFull { capacity: 2 }));
+        assert!(MerkleFrontier::<PoseidonAlgorithm>::new(0).is_err());
+    }
+
+    #[test]
+    fn test_depth_32_frontier() {
+        let mut frontier = MerkleFrontier::<PoseidonAlgorithm>::new(32).unwrap();
+        assert_eq!(frontier.capacity(), 1usize << 31);
+        let empty_root = *frontier.root();
+        frontier.insert_leaf(Fr::from(1u64)).unwrap();
+        assert_ne!(*frontier.root(), empty_root);
+    }
+
+    #[test]
+    fn test_from_tree() {
+        let mut merkle_tree = MerkleTree::<PoseidonAlgorithm>::build_empty_tree(5).unwrap();
+        for leaves_count in 0..16u64 {
+            let mut frontier = MerkleFrontier::from_tree(&merkle_tree).unwrap();
+            assert_eq!(frontier.leaves_count(), leaves_count as usize);
+            assert_eq!(Ok(frontier.root()), merkle_tree.root());
+
+            // Both keep the same roots
+            merkle_tree.insert_leaf(Fr::from(leaves_count + 10)).unwrap();
+            frontier.insert_leaf(Fr::from(leaves_count + 10)).unwrap();
+            assert_eq!(Ok(frontier.root()), merkle_tree.root());
+        }
+        assert_eq!(MerkleFrontier::from_tree(&MerkleTree::<PoseidonAlgorithm>::new()).unwrap_err(), MerkleTreeError::InvalidDepth(0));
+    }
+
+    #[test]
+    fn test_to_merkle_tree() {
+        let leaves: Vec<Fr> = (0..6u64).map(Fr::from).collect();
+        let mut frontier = MerkleFrontier::<PoseidonAlgorithm>::new(4).unwrap();
+        for leaf in leaves.iter() {
+            frontier.insert_leaf(*leaf).unwrap();
+        }
+
+        let merkle_tree = frontier.to_merkle_tree(&leaves).unwrap();
+        assert_eq!(merkle_tree.root(), Ok(frontier.root()));
+        let opening = merkle_tree.opening_orig(3).unwrap();
+        assert_eq!(merkle_tree.verify(opening.iter().collect(), 3), Ok(true));
+
+        // Wrong, missing or extra leaves are rejected
+        let missing = MerkleTreeError::LeafNotFound { index: 5, leaves_count: 5 };
+        assert_eq!(frontier.to_merkle_tree(&leaves[..5]).unwrap_err(), missing);
+        let extra = [leaves.as_slice(), &[Fr::from(6u64)]].concat();
+        let extra_error = MerkleTreeError::LeafNotFound { index: 6, leaves_count: 6 };
+        assert_eq!(frontier.to_merkle_tree(&extra).unwrap_err(), extra_error);
+        let mut wrong_leaves = leaves.clone();
+        wrong_leaves[2] = Fr::from(100u64);
+        assert_eq!(frontier.to_merkle_tree(&wrong_leaves).unwrap_err(), MerkleTreeError::RootMismatch);
+
+        // Also for a frontier without leaves
+        let empty = MerkleFrontier::<PoseidonAlgorithm>::new(4).unwrap();
+        assert_eq!(empty.to_merkle_tree(&[]).unwrap().leaves_count(), 0);
+    }
+}
@@ -1,39 +1,19 @@
-use ark_bn254::Fr;
+use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
+use core::fmt::Debug;
 
 pub trait Hasher: Clone {
-    // type Hash: Clone + PartialEq + Into<Vec<u8>> + TryFrom<Vec<u8>>;
-    type Hash: Clone + PartialEq;
+    // Node type stored in the tree. It can be a prime field element (e.g. ark_bn254::Fr) or a byte digest (e.g. [u8; 32]).
+    // Default::default() is used as the empty leaf.
+    type Hash: Copy + Eq + Default + Debug + CanonicalSerialize + CanonicalDeserialize;
 
-    fn hash<const N: usize>(data: [Fr;N]) -> Fr;
+    fn hash<const N: usize>(data: [Self::Hash; N]) -> Self::Hash;
 
-    fn leaf_hash(leaf: Fr) -> Fr {
-        // let mut concatenated: Vec<u8> = Vec::new();
-        // concatenated.push(0x00); // TODO: domain separation?
-        // concatenated.extend_from_slice(leaf);
-        // Fr(concatenated.as_slice())
+    fn leaf_hash(leaf: Self::Hash) -> Self::Hash {
+        // TODO: domain separation? (implementations may override, e.g. with a 0x00 prefix)
         Self::hash([leaf])
     }
 
-    // fn concat_and_hash(left: &Fr, right: &Fr) -> Fr {
-    //     let mut concatenated: Vec<u8> = left.clone().into();
-    //     concatenated.push(0x01);
-    //     concatenated.extend_from_slice(left.clone().into().as_slice());
-    //     concatenated.extend_from_slice(right.clone().into().as_slice());
-
-    //     Fr(concatenated.as_slice())
-    // }
-
-    fn concat_and_hash(left: &Fr, right: &Fr) -> Fr {
-        // let mut concatenated: Vec<u8> = (*left).into();
-        // let mut concatenated: Vec<u8> = (left).into_bigint().to_bytes_be();
+    fn concat_and_hash(left: &Self::Hash, right: &Self::Hash) -> Self::Hash {
         Self::hash([*left, *right])
-        // match right {
-        //     Some(right_node) => {
-        //         // let mut right_node_clone: Vec<u8> = (right_node).into_bigint().to_bytes_be();
-        //         // concatenated.append(&mut right_node_clone);
-        //         Fr([left, right_node])
-        //     }
-        //     None => left,
-        // }
     }
 }
@@ -1,80 +1,313 @@
 use crate::hasher::Hasher;
-// use sha2::{Digest, Sha256};
 use ark_bn254::Fr;
-use ark_ff::Zero;
+use ark_serialize::{CanonicalDeserialize, CanonicalSerialize, Compress, Read, SerializationError, Valid, Validate, Write};
+use crate::error::MerkleTreeError;
+use crate::frontier::MerkleFrontier;
+use crate::incremental_witness::IncrementalWitness;
+use crate::merkle_proof::{ConsistencyProof, MerkleProof, MultiProof};
 use crate::poseidon::PoseidonAlgorithm;
+use std::collections::{BTreeSet, VecDeque};
 pub type Hash = Fr;
 
+// Opening as returned by MerkleTree::opening: one (left, right) pair per level from the leaves up.
+pub type Opening<H> = Vec<(<H as Hasher>::Hash, <H as Hasher>::Hash)>;
+
+// State of a leaf slot, see MerkleTree::leaf_state. Empty and Removed slots both hold the empty leaf.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LeafState {
+    // No leaf was ever inserted in the slot.
+    Empty,
+    Occupied,
+    // A leaf was inserted and then removed with MerkleTree::remove_leaf.
+    Removed,
+}
+
+// Number of checkpoints kept by default, see with_max_checkpoints.
+pub const DEFAULT_MAX_CHECKPOINTS: usize = 100;
+
+// State of the tree when MerkleTree::checkpoint was called, and the leaves that were overwritten since
+// (until the next checkpoint). Appended leaves need no journal: they are reset to the empty leaf on rewind.
 #[derive(Debug, Clone)]
-pub struct MerkleTree
-// where
-//     T: Hasher,
+struct Checkpoint<H>
+where
+    H: Hasher,
 {
-    // hasher: H,
-    pub tree: Vec<Vec<Hash>>,
+    id: u64,
+    leaves_count: usize,
+    removed: BTreeSet<usize>,
+    root_history: VecDeque<(usize, H::Hash)>,
+    // (index, previous value) of every overwritten leaf, in order.
+    journal: Vec<(usize, H::Hash)>,
+}
+
+#[derive(Debug, Clone)]
+pub struct MerkleTree<H = PoseidonAlgorithm>
+where
+    H: Hasher,
+{
+    pub tree: Vec<Vec<H::Hash>>,
     pub leaves_count: usize,
-    empty_hashes: Vec<Hash>,
+    empty_hashes: Vec<H::Hash>,
+    // Indices of removed leaves, all below leaves_count.
+    removed: BTreeSet<usize>,
+    // The last root_history_capacity roots with the leaves_count they were computed at, oldest first.
+    // Disabled (empty) when the capacity is 0, see with_root_history.
+    root_history: VecDeque<(usize, H::Hash)>,
+    root_history_capacity: usize,
+    // Oldest first, at most max_checkpoints. Not serialized.
+    checkpoints: VecDeque<Checkpoint<H>>,
+    max_checkpoints: usize,
 }
 
-// impl<T> Default for MerkleTree<T>
-// where
-//     T: Hasher,
-// {
-//     fn default() -> Self {
-//         Self::new()
-//     }
-// }
-
-impl MerkleTree
-// where
-//     T: Hasher,
+impl<H> Default for MerkleTree<H>
+where
+    H: Hasher,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<H> MerkleTree<H>
+where
+    H: Hasher,
 {
     pub fn new() -> Self {
         Self {
             tree: Vec::new(),
             leaves_count: 0,
             empty_hashes: Vec::new(),
+            removed: BTreeSet::new(),
+            root_history: VecDeque::new(),
+            root_history_capacity: 0,
+            checkpoints: VecDeque::new(),
+            max_checkpoints: DEFAULT_MAX_CHECKPOINTS,
         }
     }
 
-    pub fn insert_leaf(&mut self, leaf: Fr) {
-        let leaves = self.tree.first_mut().unwrap();
+    // Inserts the leaf in the first unused slot and returns its index.
+    pub fn insert_leaf(&mut self, leaf: H::Hash) -> Result<usize, MerkleTreeError> {
+        let leaves = self.tree.first_mut().ok_or(MerkleTreeError::EmptyTree)?;
+        if self.leaves_count >= leaves.len() {
+            return Err(MerkleTreeError::TreeFull { capacity: leaves.len() });
+        }
         // leaves[self.leaves_count] = PoseidonAlgorithm::hash(leaf);  // Instead of draining zero nodes and pushing new, replace a zero node with the new leaf.
         leaves[self.leaves_count] = leaf; // 11/27 change before OSDI evals because leaf in our case is already Fr(=Hash).
+        self.update_path(self.leaves_count);
         self.leaves_count += 1;
-        Self::build_tree(&mut self.tree, self.leaves_count);
+        self.record_root();
+        Ok(self.leaves_count - 1)
+    }
+
+    // Inserts all leaves in consecutive unused slots and rehashes each affected node once,
+    // instead of once per leaf as with repeated insert_leaf calls.
+    // Returns the index of the first leaf and the new root. Nothing is inserted if the leaves do not all fit.
+    pub fn insert_leaves(&mut self, leaves: &[H::Hash]) -> Result<(usize, H::Hash), MerkleTreeError> {
+        let slots = self.tree.first_mut().ok_or(MerkleTreeError::EmptyTree)?;
+        let first_index = self.leaves_count;
+        if leaves.len() > slots.len() - first_index {
+            return Err(MerkleTreeError::TreeFull { capacity: slots.len() });
+        }
+        slots[first_index..first_index + leaves.len()].copy_from_slice(leaves);
+        self.update_range(first_index, first_index + leaves.len());
+        self.leaves_count += leaves.len();
+        if !leaves.is_empty() {
+            // Only the root after the whole batch is known
+            self.record_root();
+        }
+        Ok((first_index, *self.root()?))
+    }
+
+    // Same as insert_leaves for leaves that are not already in a slice.
+    pub fn insert_leaves_iter<I>(&mut self, leaves: I) -> Result<(usize, H::Hash), MerkleTreeError>
+    where
+        I: IntoIterator<Item = H::Hash>,
+    {
+        let leaves: Vec<H::Hash> = leaves.into_iter().collect();
+        self.insert_leaves(&leaves)
+    }
+
+    // Replaces an inserted leaf and rehashes its path. Returns the old value and the new root.
+    pub fn update_leaf(&mut self, leaf_index: usize, leaf: H::Hash) -> Result<(H::Hash, H::Hash), MerkleTreeError> {
+        self.check_occupied(leaf_index)?;
+        let old_leaf = std::mem::replace(&mut self.tree[0][leaf_index], leaf);
+        self.journal(leaf_index, old_leaf);
+        self.update_path(leaf_index);
+        self.record_root();
+        Ok((old_leaf, *self.root()?))
+    }
+
+    // Resets an inserted leaf to the empty leaf and rehashes its path. The indices of the other leaves
+    // do not change and the slot is not reused by insert_leaf. Returns the removed value and the new root.
+    pub fn remove_leaf(&mut self, leaf_index: usize) -> Result<(H::Hash, H::Hash), MerkleTreeError> {
+        self.check_occupied(leaf_index)?;
+        let old_leaf = std::mem::replace(&mut self.tree[0][leaf_index], self.empty_hashes[0]);
+        self.journal(leaf_index, old_leaf);
+        self.update_path(leaf_index);
+        self.removed.insert(leaf_index);
+        self.record_root();
+        Ok((old_leaf, *self.root()?))
+    }
+
+    // Tells removed slots apart from never used ones, which hold the same value.
+    pub fn leaf_state(&self, leaf_index: usize) -> Result<LeafState, MerkleTreeError> {
+        self.check_index(leaf_index)?;
+        if leaf_index >= self.leaves_count {
+            Ok(LeafState::Empty)
+        } else if self.removed.contains(&leaf_index) {
+            Ok(LeafState::Removed)
+        } else {
+            Ok(LeafState::Occupied)
+        }
+    }
+
+    // Indices of the removed leaves, in increasing order.
+    pub fn removed_leaves(&self) -> impl Iterator<Item = usize> + '_ {
+        self.removed.iter().copied()
+    }
+
+    // Keeps the last `capacity` roots (including the current one) so that proofs against recent roots can still
+    // be accepted, see is_known_root. A root is recorded after every change of the tree. 0 disables the history.
+    pub fn with_root_history(mut self, capacity: usize) -> Self {
+        self.root_history_capacity = capacity;
+        self.root_history.clear();
+        self.record_root();
+        self
+    }
+
+    fn record_root(&mut self) {
+        if self.root_history_capacity == 0 {
+            return;
+        }
+        if let Ok(root) = self.root() {
+            let entry = (self.leaves_count, *root);
+            if self.root_history.len() == self.root_history_capacity {
+                self.root_history.pop_front();
+            }
+            self.root_history.push_back(entry);
+        }
+    }
+
+    // Whether root is one of the roots in the history. Without a history only the current root is known.
+    pub fn is_known_root(&self, root: &H::Hash) -> bool {
+        self.root_history.iter().any(|(_, known)| known == root) || self.root() == Ok(root)
+    }
+
+    // The root recorded when the tree had leaves_count leaves, if it is still in the history.
+    // If the tree changed several times at that size (update_leaf, remove_leaf), the latest root is returned.
+    pub fn root_at(&self, leaves_count: usize) -> Option<&H::Hash> {
+        self.root_history
+            .iter()
+            .rev()
+            .find(|(count, _)| *count == leaves_count)
+            .map(|(_, root)| root)
+    }
+
+    pub fn root_history_capacity(&self) -> usize {
+        self.root_history_capacity
+    }
+
+    // Keeps at most max_checkpoints checkpoints, dropping the oldest ones first. With 0, checkpoint returns
+    // CheckpointsDisabled.
+    pub fn with_max_checkpoints(mut self, max_checkpoints: usize) -> Self {
+        self.max_checkpoints = max_checkpoints;
+        while self.checkpoints.len() > max_checkpoints {
+            self.checkpoints.pop_front();
+        }
+        self
+    }
+
+    // Records the current state of the tree (leaves, leaves_count, removed leaves and root history) so that
+    // rewind_to(id) can restore it, e.g. when a block is reorged out. Ids must be strictly increasing.
+    pub fn checkpoint(&mut self, id: u64) -> Result<(), MerkleTreeError> {
+        if let Some(last) = self.checkpoints.back() {
+            if id <= last.id {
+                return Err(MerkleTreeError::CheckpointOutOfOrder { id, last: last.id });
+            }
+        }
+        if self.max_checkpoints == 0 {
+            return Err(MerkleTreeError::CheckpointsDisabled);
+        }
+        if self.checkpoints.len() == self.max_checkpoints {
+            self.checkpoints.pop_front();
+        }
+        self.checkpoints.push_back(Checkpoint {
+            id,
+            leaves_count: self.leaves_count,
+            removed: self.removed.clone(),
+            root_history: self.root_history.clone(),
+            journal: Vec::new(),
+        });
+        Ok(())
     }
 
-    pub fn build_empty_tree(depth: u32) -> MerkleTree {
+    // Restores the state recorded by checkpoint(id) and drops the later checkpoints. The checkpoint itself is kept,
+    // so it can be rewound to again. Only the paths of the changed leaves are rehashed. Returns the restored root.
+    pub fn rewind_to(&mut self, id: u64) -> Result<H::Hash, MerkleTreeError> {
+        let position = self
+            .checkpoints
+            .iter()
+            .position(|checkpoint| checkpoint.id == id)
+            .ok_or(MerkleTreeError::UnknownCheckpoint(id))?;
+
+        // Undo the overwrites, latest first
+        let mut touched = Vec::new();
+        for checkpoint in self.checkpoints.iter_mut().skip(position).rev() {
+            for (leaf_index, old_leaf) in checkpoint.journal.drain(..).rev() {
+                self.tree[0][leaf_index] = old_leaf;
+                touched.push(leaf_index);
+            }
+        }
+        self.checkpoints.truncate(position + 1);
+        let checkpoint = &self.checkpoints[position];
+
+        // Drop the leaves appended since the checkpoint
+        let (old_count, new_count) = (checkpoint.leaves_count, self.leaves_count);
+        for leaf in self.tree[0][old_count..new_count].iter_mut() {
+            *leaf = self.empty_hashes[0];
+        }
+        self.removed = checkpoint.removed.clone();
+        self.root_history = checkpoint.root_history.clone();
+        self.leaves_count = old_count;
+
+        touched.retain(|&leaf_index| leaf_index < old_count);
+        touched.sort_unstable();
+        touched.dedup();
+        for leaf_index in touched {
+            self.update_path(leaf_index);
+        }
+        self.update_range(old_count, new_count);
+        Ok(*self.root()?)
+    }
+
+    // Ids of the retained checkpoints, oldest first.
+    pub fn checkpoint_ids(&self) -> impl Iterator<Item = u64> + '_ {
+        self.checkpoints.iter().map(|checkpoint| checkpoint.id)
+    }
+
+    // Remembers the previous value of an overwritten leaf for rewind_to.
+    fn journal(&mut self, leaf_index: usize, old_leaf: H::Hash) {
+        if let Some(checkpoint) = self.checkpoints.back_mut() {
+            checkpoint.journal.push((leaf_index, old_leaf));
+        }
+    }
+
+    pub fn build_empty_tree(depth: u32) -> Result<MerkleTree<H>, MerkleTreeError> {
+        check_depth(depth)?;
         let mut tree = MerkleTree::new();
     
         // Start from the bottom layer (leaves)
-        let num_leaves = 1usize << (depth - 1);
-        // let empty_leaf = PoseidonAlgorithm::hash([Fr::zero(), Fr::zero()]);
-        let mut current_level = vec![Fr::zero(); num_leaves];
-    
-        // Push each level from leaves to root
-        for _ in 0..depth {
-            tree.tree.push(current_level.clone());
-            tree.empty_hashes.push(current_level[0]);   // Save empty node at each level
-
-            // Build the next level up
-            let mut next_level = Vec::with_capacity(current_level.len() / 2);
-            for pair in current_level.chunks(2) {
-                let h = if pair.len() == 2 {
-                    PoseidonAlgorithm::hash([pair[0], pair[1]])
-                } else {
-                    // handle odd node (pad with zero)
-                    PoseidonAlgorithm::hash([pair[0], Fr::zero()])
-                };
-                next_level.push(h);
-            }
+        let mut num_nodes = 1usize << (depth - 1);
+        tree.empty_hashes = empty_hashes::<H>(depth);   // Save empty node at each level
     
-            current_level = next_level;
+        // Push each level from leaves to root. All nodes of a level are equal to the empty node of that level.
+        for empty_node in tree.empty_hashes.iter() {
+            tree.tree.push(empty_level(*empty_node, num_nodes, depth)?);
+            num_nodes = num_nodes.div_ceil(2);
         }
     
         tree.leaves_count = 0;  // Note: leaves_count is for real leaves that are actually inserted (log tree roots)
-        tree
+        Ok(tree)
     }
 
     // pub fn build_empty_tree(depth: u32) -> MerkleTree {
@@ -89,45 +322,34 @@ impl MerkleTree
     //     tree
     // }
 
-    fn build_tree(tree: &mut Vec<Vec<Hash>>, leaves_count: usize) {
-        tree.drain(1..);    // Remove all upper levels because they'll be rebuilt from new leaves.
-        let mut idx = 0;
-        // If there are odd number of leaves (except 1), append a zero node.
-        // (original behavior was to duplicate the last leaf)
-        // It makes it easier to compute the merkle proof of the last leaf in a tree with odd number of leaves
-        // NOTE: This does NOT affect the tree when inserting a new leaf as the duplicated leaf is removed before a new leaf is inserted
-        
-        // if leaves_count > 1 && leaves_count % 2 != 0 {
-        //     // let last = tree.get(0).unwrap().last().unwrap().clone();
-        //     tree.get_mut(0).unwrap().push(Fr::zero());
-        // }    // Note: all leaves have placeholders
-
-        loop {
-            // Keep looping until you reach a level with a single node
-            let current_layer = tree.get(idx).unwrap().clone();
-            let n = current_layer.len();
-            if n == 1 {
-                break;
-            }
-
-            let mut row = Vec::with_capacity((n + 1) / 2);
-
-            let mut i = 0;
+    // Rehashes the nodes on the path from the leaf at `leaf_index` to the root: depth - 1 hashes.
+    // Siblings that were never touched still hold the empty node of their level (see build_empty_tree).
+    fn update_path(&mut self, leaf_index: usize) {
+        let mut idx = leaf_index;
+        for level in 0..self.tree.len() - 1 {
+            let left = idx & !1;
+            let current_layer = &self.tree[level];
+            let internal_node = H::concat_and_hash(&current_layer[left], &current_layer[left + 1]);
+            idx /= 2;
+            self.tree[level + 1][idx] = internal_node;
+        }
+    }
 
-            while i < n {
-                let internal_node = PoseidonAlgorithm::concat_and_hash(&current_layer[i], &current_layer[i + 1]);
-                row.push(internal_node);
-                i += 2;
+    // Rehashes the nodes above the leaves in start..end, each once: at every level only the parents
+    // of the range (which shrinks by half per level) are recomputed.
+    fn update_range(&mut self, start: usize, end: usize) {
+        if start >= end {
+            return;
+        }
+        let (mut first, mut last) = (start, end - 1);
+        for level in 0..self.tree.len() - 1 {
+            first /= 2;
+            last /= 2;
+            for idx in first..=last {
+                let current_layer = &self.tree[level];
+                let internal_node = H::concat_and_hash(&current_layer[2 * idx], &current_layer[2 * idx + 1]);
+                self.tree[level + 1][idx] = internal_node;
             }
-
-            // Note: all leaves have placeholders
-            // if row.len() > 1 && row.len() % 2 != 0 {
-            //     // let last = row.last().unwrap().clone();
-            //     row.push(Fr::zero());
-            // }
-            tree.push(row);
-            // Move to the next level
-            idx += 1;
         }
     }
 
@@ -139,35 +361,60 @@ impl MerkleTree
         self.tree.len()
     }
 
-    pub fn value(&self, leaf_index: usize) -> Option<&Hash> {
-        self.tree
-            .first()
-            .expect("There are no leaves in the tree!")
-            .get(leaf_index)
+    // Maximum number of leaves (slots at the leaf level).
+    pub fn capacity(&self) -> usize {
+        self.tree.first().map_or(0, |leaves| leaves.len())
+    }
+
+    // Errors unless leaf_index addresses a leaf slot. Unused slots hold the empty leaf and are valid.
+    fn check_index(&self, leaf_index: usize) -> Result<(), MerkleTreeError> {
+        if self.tree.is_empty() {
+            return Err(MerkleTreeError::EmptyTree);
+        }
+        if leaf_index >= self.capacity() {
+            return Err(MerkleTreeError::IndexOutOfRange { index: leaf_index, capacity: self.capacity() });
+        }
+        Ok(())
+    }
+
+    // Errors unless a leaf was inserted at leaf_index and not removed since.
+    fn check_occupied(&self, leaf_index: usize) -> Result<(), MerkleTreeError> {
+        match self.leaf_state(leaf_index)? {
+            LeafState::Occupied => Ok(()),
+            LeafState::Empty => Err(MerkleTreeError::LeafNotFound { index: leaf_index, leaves_count: self.leaves_count }),
+            LeafState::Removed => Err(MerkleTreeError::LeafRemoved(leaf_index)),
+        }
     }
 
-    pub fn root(&self) -> Option<&Hash> {
+    pub fn value(&self, leaf_index: usize) -> Result<&H::Hash, MerkleTreeError> {
+        self.check_index(leaf_index)?;
+        Ok(&self.tree[0][leaf_index])
+    }
+
+    pub fn root(&self) -> Result<&H::Hash, MerkleTreeError> {
         self.tree
             .last()
-            .expect("There are no leaves in the tree!")
-            .first()
+            .and_then(|level| level.first())
+            .ok_or(MerkleTreeError::EmptyTree)
     }
 
-    pub fn opening_orig(&self, mut leaf_index: usize) -> Vec<Hash> {
+    pub fn opening_orig(&self, mut leaf_index: usize) -> Result<Vec<H::Hash>, MerkleTreeError> {
+        self.check_index(leaf_index)?;
         let mut opening = Vec::new();
         // Iterate over all level until the root
-        for level in self.tree.split_last().unwrap().1.iter() {
-            if leaf_index % 2 != 0 {
-                opening.push(*level.get(leaf_index - 1).unwrap());
+        for level in self.tree[..self.tree.len() - 1].iter() {
+            if !leaf_index.is_multiple_of(2) {
+                opening.push(level[leaf_index - 1]);
             } else {
-                opening.push(*level.get(leaf_index + 1).unwrap());
+                opening.push(level[leaf_index + 1]);
             }
             leaf_index /= 2;
         }
-        opening
+        Ok(opening)
     }
 
-    pub fn opening(&self, leaf_index: u32) -> Vec<(Hash, Hash)> {
+    pub fn opening(&self, leaf_index: u32) -> Result<Opening<H>, MerkleTreeError> {
+        self.check_index(leaf_index as usize)?;
         let mut path = Vec::new();
         // let tree_index = convert_index_to_last_level(index, N); // Note: It's given index = Binary(h_i)
 
@@ -175,14 +422,14 @@ impl MerkleTree
         let mut current_node = leaf_index;
         // let mut level_count = 0;
         // while !is_root(current_node) {
-        for level in self.tree.split_last().unwrap().1.iter() {
+        for level in self.tree[..self.tree.len() - 1].iter() {
             // println!("level {:?}", level_count);
             let sibling_node = sibling(current_node).unwrap();
             // let empty_hash = &self.empty_hashes[level_count];
             // println!("current node {:?}", current_node);
-            let current = level.get(current_node as usize).cloned().expect("Expected current node to exist.");
+            let current = level[current_node as usize];
             // println!("current {:?}", current);
-            let sibling = level.get(sibling_node as usize).cloned().expect("Expected sibling node to exist.");
+            let sibling = level[sibling_node as usize];
             // println!("sibling {:?}", sibling);
             if is_left_child(current_node) {
                 // path[level] = (current, sibling);
@@ -195,27 +442,288 @@ impl MerkleTree
             // level_count += 1;
         }
 
-        path
+        Ok(path)
     }
     
 
-    pub fn verify(&self, proof: Vec<&Hash>, mut leaf_index: usize) -> bool {
-        let mut prev: ark_ff::Fp<ark_ff::MontBackend<ark_bn254::FrConfig, 4>, 4>= self.tree.first().unwrap().get(leaf_index).unwrap().clone();
+    // Opening of the leaf at leaf_index as a MerkleProof, which can be checked with merkle_proof::verify.
+    pub fn proof(&self, leaf_index: usize) -> Result<MerkleProof<H>, MerkleTreeError> {
+        let leaf = *self.value(leaf_index)?;
+        Ok(MerkleProof::from_opening_orig(leaf_index, leaf, self.opening_orig(leaf_index)?))
+    }
+
+    // Witness of an inserted leaf that can follow the next appends without the tree, see IncrementalWitness.
+    pub fn witness(&self, leaf_index: usize) -> Result<IncrementalWitness<H>, MerkleTreeError> {
+        self.check_occupied(leaf_index)?;
+        let siblings = self.opening_orig(leaf_index)?;
+        let frontier = MerkleFrontier::from_tree(self)?;
+        Ok(IncrementalWitness::new(leaf_index, self.tree[0][leaf_index], siblings, frontier))
+    }
+
+    // Opening of several leaves in which every node appears once, see MultiProof. Duplicate indices are ignored.
+    pub fn multi_opening(&self, leaf_indices: &[u32]) -> Result<MultiProof<H>, MerkleTreeError> {
+        let mut indices: Vec<usize> = leaf_indices.iter().map(|&index| index as usize).collect();
+        for &index in indices.iter() {
+            self.check_index(index)?;
+        }
+        indices.sort_unstable();
+        indices.dedup();
+        let leaves = indices.iter().map(|&index| self.tree[0][index]).collect();
+
+        let mut siblings = Vec::new();
+        let mut current = indices.clone();
+        for level in self.tree[..self.tree.len().saturating_sub(1)].iter() {
+            let mut i = 0;
+            while i < current.len() {
+                let index = current[i];
+                if index.is_multiple_of(2) && current.get(i + 1) == Some(&(index + 1)) {
+                    // The sibling is on a path too
+                    i += 1;
+                } else {
+                    siblings.push(level[index ^ 1]);
+                }
+                i += 1;
+            }
+            current = current.iter().map(|index| index / 2).collect();
+            current.dedup();
+        }
+
+        Ok(MultiProof::new(self.depth(), indices, leaves, siblings))
+    }
+
+    // Root of the tree when it contained only its first tree_size leaves (0 for the empty tree), recomputed from
+    // the current leaves: if a leaf below tree_size was updated or removed since, this is not the root the tree
+    // had back then, and no error is returned.
+    pub fn historical_root(&self, tree_size: usize) -> Result<H::Hash, MerkleTreeError> {
+        if tree_size == 0 {
+            return Ok(*self.empty_hashes.last().ok_or(MerkleTreeError::EmptyTree)?);
+        }
+        self.check_size(tree_size, self.leaves_count)?;
+        Ok(self.node_at_size(self.depth() - 1, 0, tree_size))
+    }
+
+    // Opening of the leaf at leaf_index against historical_root(tree_size), in the format of opening.
+    // Requires leaf_index < tree_size <= leaves_count. Built from the current leaves, so it does not verify against
+    // the root published at tree_size if a leaf below tree_size was updated or removed since.
+    pub fn opening_at(&self, leaf_index: u32, tree_size: usize) -> Result<Opening<H>, MerkleTreeError> {
+        let proof = self.proof_at(leaf_index as usize, tree_size)?;
+        Ok(proof.to_opening())
+    }
+
+    // Same as opening_at, as a MerkleProof.
+    pub fn proof_at(&self, leaf_index: usize, tree_size: usize) -> Result<MerkleProof<H>, MerkleTreeError> {
+        self.check_size(tree_size, self.leaves_count)?;
+        if leaf_index >= tree_size {
+            return Err(MerkleTreeError::LeafNotFound { index: leaf_index, leaves_count: tree_size });
+        }
+
+        let mut siblings = Vec::with_capacity(self.depth() - 1);
+        let mut index = leaf_index;
+        for level in 0..self.depth() - 1 {
+            siblings.push(self.node_at_size(level, index ^ 1, tree_size));
+            index /= 2;
+        }
+        Ok(MerkleProof::new(leaf_index, self.tree[0][leaf_index], siblings))
+    }
+
+    // Proof that the tree at new_size leaves extends the tree at old_size leaves, see ConsistencyProof.
+    // Requires 0 < old_size <= new_size <= leaves_count. Past trees are recomputed from the current leaves, so if
+    // a leaf below old_size was updated or removed since, the proof does not match the root published back then.
+    pub fn consistency_proof(&self, old_size: usize, new_size: usize) -> Result<ConsistencyProof<H>, MerkleTreeError> {
+        self.check_size(new_size, self.leaves_count)?;
+        self.check_size(old_size, new_size)?;
+
+        let mut siblings = Vec::with_capacity(self.depth() - 1);
+        let mut index = old_size - 1;
+        for level in 0..self.depth() - 1 {
+            siblings.push(self.node_at_size(level, index ^ 1, new_size));
+            index /= 2;
+        }
+        Ok(ConsistencyProof::new(old_size, new_size, self.tree[0][old_size - 1], siblings))
+    }
+
+    // Errors unless 0 < size <= max.
+    fn check_size(&self, size: usize, max: usize) -> Result<(), MerkleTreeError> {
+        if self.tree.is_empty() {
+            return Err(MerkleTreeError::EmptyTree);
+        }
+        if size == 0 || size > max {
+            return Err(MerkleTreeError::InvalidTreeSize { size, max });
+        }
+        Ok(())
+    }
+
+    // The node at (level, index) when the tree had only its first `size` leaves: nodes to the right of the leaf at
+    // size - 1 were empty and nodes to its left were as they are now. Only the nodes above that leaf are rehashed.
+    fn node_at_size(&self, level: usize, index: usize, size: usize) -> H::Hash {
+        let first_leaf = index << level;
+        let last_leaf = first_leaf + (1usize << level) - 1;
+        if first_leaf >= size {
+            self.empty_hashes[level]
+        } else if last_leaf < size {
+            self.tree[level][index]
+        } else {
+            let left = self.node_at_size(level - 1, 2 * index, size);
+            let right = self.node_at_size(level - 1, 2 * index + 1, size);
+            H::concat_and_hash(&left, &right)
+        }
+    }
+
+    pub fn verify(&self, proof: Vec<&H::Hash>, mut leaf_index: usize) -> Result<bool, MerkleTreeError> {
+        let mut prev: H::Hash = *self.value(leaf_index)?;
+        if proof.len() != self.depth() - 1 {
+            return Err(MerkleTreeError::InvalidProofLength { expected: self.depth() - 1, found: proof.len() });
+        }
         for node in proof.into_iter() {
-            if leaf_index % 2 == 0 {
-                prev = PoseidonAlgorithm::concat_and_hash(&prev, node);
+            if leaf_index.is_multiple_of(2) {
+                prev = H::concat_and_hash(&prev, node);
             } else {
-                prev = PoseidonAlgorithm::concat_and_hash(node, &prev);
+                prev = H::concat_and_hash(node, &prev);
             }
             leaf_index /= 2;
         }
-        prev == self.tree.last().unwrap().first().unwrap().clone()
+        Ok(prev == *self.root()?)
+    }
+}
+
+// Trees are serialized as their levels, leaves_count, empty_hashes, removed leaves and root history, e.g. to persist them between runs.
+impl<H> CanonicalSerialize for MerkleTree<H>
+where
+    H: Hasher,
+{
+    fn serialize_with_mode<W: Write>(&self, mut writer: W, compress: Compress) -> Result<(), SerializationError> {
+        self.tree.serialize_with_mode(&mut writer, compress)?;
+        self.leaves_count.serialize_with_mode(&mut writer, compress)?;
+        self.empty_hashes.serialize_with_mode(&mut writer, compress)?;
+        self.removed.serialize_with_mode(&mut writer, compress)?;
+        self.root_history_capacity.serialize_with_mode(&mut writer, compress)?;
+        self.root_history.len().serialize_with_mode(&mut writer, compress)?;
+        for entry in self.root_history.iter() {
+            entry.serialize_with_mode(&mut writer, compress)?;
+        }
+        Ok(())
+    }
+
+    fn serialized_size(&self, compress: Compress) -> usize {
+        self.tree.serialized_size(compress)
+            + self.leaves_count.serialized_size(compress)
+            + self.empty_hashes.serialized_size(compress)
+            + self.removed.serialized_size(compress)
+            + self.root_history_capacity.serialized_size(compress)
+            + self.root_history.len().serialized_size(compress)
+            + self.root_history.iter().map(|entry| entry.serialized_size(compress)).sum::<usize>()
+    }
+}
+
+impl<H> Valid for MerkleTree<H>
+where
+    H: Hasher,
+{
+    // Checks the shape of the tree (levels halving up to a single root, one empty node per level), the empty nodes
+    // and that removed leaves are inserted slots holding the empty leaf. Inner nodes are not rehashed.
+    fn check(&self) -> Result<(), SerializationError> {
+        self.tree.check()?;
+        self.empty_hashes.check()?;
+        if self.root_history.len() > self.root_history_capacity
+            || self.root_history.iter().any(|(count, root)| *count > self.leaves_count || root.check().is_err())
+        {
+            return Err(SerializationError::InvalidData);
+        }
+
+        if self.tree.is_empty() {
+            if !self.empty_hashes.is_empty() || self.leaves_count != 0 || !self.removed.is_empty() {
+                return Err(SerializationError::InvalidData);
+            }
+            return Ok(());
+        }
+
+        let depth = self.tree.len();
+        if depth > usize::BITS as usize
+            || self.tree[0].len() != 1usize << (depth - 1)
+            || self.leaves_count > self.tree[0].len()
+            || self.empty_hashes.len() != depth
+        {
+            return Err(SerializationError::InvalidData);
+        }
+        for level in 1..depth {
+            if self.tree[level].len() != self.tree[level - 1].len().div_ceil(2)
+                || self.empty_hashes[level] != H::concat_and_hash(&self.empty_hashes[level - 1], &self.empty_hashes[level - 1])
+            {
+                return Err(SerializationError::InvalidData);
+            }
+        }
+        if self.removed.iter().any(|&index| index >= self.leaves_count || self.tree[0][index] != self.empty_hashes[0]) {
+            return Err(SerializationError::InvalidData);
+        }
+        Ok(())
     }
 }
 
+impl<H> CanonicalDeserialize for MerkleTree<H>
+where
+    H: Hasher,
+{
+    fn deserialize_with_mode<R: Read>(
+        mut reader: R,
+        compress: Compress,
+        validate: Validate,
+    ) -> Result<Self, SerializationError> {
+        let tree = Vec::<Vec<H::Hash>>::deserialize_with_mode(&mut reader, compress, validate)?;
+        let leaves_count = usize::deserialize_with_mode(&mut reader, compress, validate)?;
+        let empty_hashes = Vec::<H::Hash>::deserialize_with_mode(&mut reader, compress, validate)?;
+        let removed = BTreeSet::<usize>::deserialize_with_mode(&mut reader, compress, validate)?;
+        let root_history_capacity = usize::deserialize_with_mode(&mut reader, compress, validate)?;
+        let history_len = usize::deserialize_with_mode(&mut reader, compress, validate)?;
+        let root_history = (0..history_len)
+            .map(|_| <(usize, H::Hash)>::deserialize_with_mode(&mut reader, compress, validate))
+            .collect::<Result<VecDeque<_>, _>>()?;
+        let merkle_tree = MerkleTree {
+            tree,
+            leaves_count,
+            empty_hashes,
+            removed,
+            root_history,
+            root_history_capacity,
+            checkpoints: VecDeque::new(),
+            max_checkpoints: DEFAULT_MAX_CHECKPOINTS,
+        };
+        if let Validate::Yes = validate {
+            merkle_tree.check()?;
+        }
+        Ok(merkle_tree)
+    }
+}
+
+pub(crate) fn check_depth(depth: u32) -> Result<(), MerkleTreeError> {
+    if depth == 0 || depth > usize::BITS {
+        return Err(MerkleTreeError::InvalidDepth(depth));
+    }
+    Ok(())
+}
+
+// Level of `num_nodes` copies of the empty node, or InvalidDepth if the allocator cannot provide it.
+pub(crate) fn empty_level<T: Clone>(empty_node: T, num_nodes: usize, depth: u32) -> Result<Vec<T>, MerkleTreeError> {
+    let mut level = Vec::new();
+    level.try_reserve_exact(num_nodes).map_err(|_| MerkleTreeError::InvalidDepth(depth))?;
+    level.resize(num_nodes, empty_node);
+    Ok(level)
+}
+
+// Empty node at each level of a tree of the given depth, from the leaves (default hash) up to the root.
+pub(crate) fn empty_hashes<H: Hasher>(depth: u32) -> Vec<H::Hash> {
+    let mut empty_node = H::Hash::default();
+    // let empty_leaf = PoseidonAlgorithm::hash([Fr::zero(), Fr::zero()]);
+    let mut hashes = Vec::with_capacity(depth as usize);
+    for _ in 0..depth {
+        hashes.push(empty_node);
+        empty_node = H::concat_and_hash(&empty_node, &empty_node);
+    }
+    hashes
+}
+
 // Returns true iff the given index represents a left child.
 fn is_left_child(index: u32) -> bool {
-    index % 2 == 0
+    index.is_multiple_of(2)
 }
 
 // Returns the index of the sibling, given an index.
@@ -238,160 +746,523 @@ fn parent (index: u32) -> Option<u32> {
     }
 }
 
-// #[derive(Clone)]
-// pub struct DefaultHasher {}
-
-// impl Hasher for DefaultHasher {
-//     type Hash = Vec<u8>;
-
-//     fn hash(bytes: &[u8]) -> Self::Hash {
-//         Sha256::digest(bytes).to_vec()
-//     }
-// }
-
-// #[cfg(test)]
-// pub mod tests {
-
-//     use super::*;
-
-//     pub fn build_mock_tree<T: Hasher>(mock_merkle_tree: &mut MerkleTree<T>, leaves_count: usize) {
-//         (0..leaves_count)
-//             .for_each(|leaf| mock_merkle_tree.insert_leaf(leaf.to_string().as_bytes()));
-//     }
-
-//     pub fn get_leaf(index: usize) -> Vec<u8> {
-//         let mut res: Vec<u8> = Vec::new();
-//         res.push(0x00);
-//         res.extend_from_slice(index.to_string().as_bytes());
-//         Sha256::digest(res.as_slice()).to_vec()
-//     }
-
-//     #[test]
-
-//     fn test_add_one_leaf() {
-//         let mut merkle_tree = MerkleTree::<DefaultHasher>::new();
-//         // Assert the merkle tree must be empty before inserting a leaf
-//         assert_eq!(merkle_tree.leaves_count(), 0);
-//         // Let's build a merkle tree with one node
-//         merkle_tree.insert_leaf("hello".as_bytes());
-//         // the leaf must be the root in this case
-//         assert_eq!(merkle_tree.root(), merkle_tree.value(0));
-//         assert_eq!(merkle_tree.leaves_count(), 1)
-//     }
-
-//     #[test]
-//     fn test_add_two_leaves() {
-//         let mut merkle_tree = MerkleTree::<DefaultHasher>::new();
-//         // Assert the merkle tree must be empty before inserting a leaf
-//         assert_eq!(merkle_tree.leaves_count(), 0);
-//         // Build a mock merkle tree with 2 leaves
-//         build_mock_tree(&mut merkle_tree, 2);
-
-//         // The tree height is expected to be 2
-//         assert_eq!(merkle_tree.depth(), 2);
-//         assert_eq!(merkle_tree.leaves_count(), 2);
-
-//         // Check if the input leaf values (hashes) are stored correctly in the tree
-//         assert_eq!(merkle_tree.value(0), Some(&get_leaf(0)));
-//         assert_eq!(merkle_tree.value(1), Some(&get_leaf(1)));
-//     }
-
-//     #[test]
-//     fn test_add_three_leaves() {
-//         let mut merkle_tree = MerkleTree::<DefaultHasher>::new();
-
-//         // // Assert the merkle tree must be empty before inserting a leaf
-//         assert_eq!(merkle_tree.leaves_count(), 0);
-
-//         // Build a mock merkle tree with 3 leaves
-//         build_mock_tree(&mut merkle_tree, 3);
-
-//         assert_eq!(merkle_tree.leaves_count(), 3);
-//         // The expected tree height should 3
-//         //     root
-//         //     /  \
-//         //   i_0  i_1
-//         //  / \   / \
-//         //  0 1  2  2_copy
-//         assert_eq!(merkle_tree.depth(), 3);
-
-//         // Check if the input leaf values (hashes) are stored correctly in the tree
-//         assert_eq!(merkle_tree.value(0), Some(&get_leaf(0)));
-//         assert_eq!(merkle_tree.value(1), Some(&get_leaf(1)));
-//         assert_eq!(merkle_tree.value(2), Some(&get_leaf(2)));
-
-//         // get merkle proof of the leaf at index 1
-//         let opening = merkle_tree.opening(1);
-//         assert!(merkle_tree.verify(opening, 1));
-//     }
-
-//     #[test]
-//     fn test_add_five_leaves() {
-//         let mut merkle_tree = MerkleTree::<DefaultHasher>::new();
-
-//         // // Assert the merkle tree must be empty before inserting a leaf
-//         assert_eq!(merkle_tree.leaves_count(), 0);
-
-//         // Build a mock merkle tree with 5 leaves
-//         build_mock_tree(&mut merkle_tree, 5);
-
-//         assert_eq!(merkle_tree.leaves_count(), 5);
-//         // The expected tree height should 4
-//         //          root
-//         //       /       \
-//         //     i_3       i_4
-//         //    /   \     /  \
-//         //  i_0  i_1  i_2 i_2_copy
-//         //  / \  / \  / \
-//         //  0 1  2 3  4 4_copy
-//         assert_eq!(merkle_tree.depth(), 4);
-
-//         // Check if the input leaf values (hashes) are stored correctly in the tree
-//         assert_eq!(merkle_tree.value(0), Some(&get_leaf(0)));
-//         assert_eq!(merkle_tree.value(1), Some(&get_leaf(1)));
-//         assert_eq!(merkle_tree.value(4), Some(&get_leaf(4)));
-
-//         // get merkle proof of the leaf at index 4
-//         let opening = merkle_tree.opening(4);
-//         assert!(merkle_tree.verify(opening, 4));
-//     }
-
-//     #[test]
-//     fn test_add_999_leaves() {
-//         let mut merkle_tree = MerkleTree::<DefaultHasher>::new();
-
-//         // // Assert the merkle tree must be empty before inserting a leaf
-//         assert_eq!(merkle_tree.leaves_count(), 0);
-
-//         // Build a mock merkle tree with 3 leaves
-//         build_mock_tree(&mut merkle_tree, 999);
-
-//         // Check if the input leaf values (hashes) are stored correctly in the tree
-//         assert_eq!(merkle_tree.value(0), Some(&get_leaf(0)));
-//         assert_eq!(merkle_tree.value(1), Some(&get_leaf(1)));
-//         assert_eq!(merkle_tree.value(4), Some(&get_leaf(4)));
-//         assert_eq!(merkle_tree.value(101), Some(&get_leaf(101)));
-//         assert_eq!(merkle_tree.value(567), Some(&get_leaf(567)));
-//         assert_eq!(merkle_tree.value(789), Some(&get_leaf(789)));
-
-//         // get merkle proof of the leaf at index 1
-//         let opening = merkle_tree.opening(579);
-//         assert!(merkle_tree.verify(opening, 579));
-//     }
-
-//     #[test]
-//     fn test_merkle_proof_1176_leaves() {
-//         let mut merkle_tree = MerkleTree::<DefaultHasher>::new();
-
-//         // Assert the merkle tree must be empty before inserting a leaf
-//         assert_eq!(merkle_tree.leaves_count(), 0);
-
-//         // Build a mock merkle tree with 1176 leaves
-//         build_mock_tree(&mut merkle_tree, 1176);
-
-//         assert_eq!(merkle_tree.leaves_count(), 1176);
-//         // get merkle proof of the leaf at index 999
-//         let opening = merkle_tree.opening(999);
-//         assert!(merkle_tree.verify(opening, 999));
-//     }
-// }
+#[cfg(test)]
+pub mod tests {
+
+    use super::*;
+    use crate::sha256::Sha256Hasher;
+    use crate::test_utils::build_mock_tree;
+    use sha2::{Digest, Sha256};
+
+    pub fn get_leaf(index: usize) -> [u8; 32] {
+        let mut res: Vec<u8> = Vec::new();
+        res.push(0x00);
+        res.extend_from_slice(index.to_string().as_bytes());
+        Sha256::digest(res.as_slice()).into()
+    }
+
+    // Root of a tree of the given depth recomputed from scratch, level by level.
+    fn full_rebuild_root(leaves: &[Fr], depth: u32) -> Fr {
+        let mut level = vec![Fr::from(0u64); 1usize << (depth - 1)];
+        level[..leaves.len()].copy_from_slice(leaves);
+        while level.len() > 1 {
+            level = level
+                .chunks(2)
+                .map(|pair| PoseidonAlgorithm::concat_and_hash(&pair[0], &pair[1]))
+                .collect();
+        }
+        level[0]
+    }
+
+    #[test]
+    fn test_incremental_insert_matches_full_rebuild() {
+        let mut merkle_tree = MerkleTree::<PoseidonAlgorithm>::build_empty_tree(5).unwrap();
+        let leaves: Vec<Fr> = (0..13u64).map(|leaf| Fr::from(leaf * 7 + 1)).collect();
+        for (i, leaf) in leaves.iter().enumerate() {
+            merkle_tree.insert_leaf(*leaf).unwrap();
+            assert_eq!(merkle_tree.root(), Ok(&full_rebuild_root(&leaves[..=i], 5)));
+        }
+    }
+
+    #[test]
+    fn test_insert_leaves_matches_sequential_inserts() {
+        let leaves: Vec<Fr> = (0..23u64).map(|leaf| Fr::from(leaf * 5 + 2)).collect();
+        let mut sequential = MerkleTree::<PoseidonAlgorithm>::build_empty_tree(6).unwrap();
+        for leaf in leaves.iter() {
+            sequential.insert_leaf(*leaf).unwrap();
+        }
+
+        // One batch, and batches of different sizes that start at odd and even indices
+        let mut batched = MerkleTree::<PoseidonAlgorithm>::build_empty_tree(6).unwrap();
+        assert_eq!(batched.insert_leaves(&leaves), Ok((0, *sequential.root().unwrap())));
+        assert_eq!(batched.tree, sequential.tree);
+        assert_eq!(batched.leaves_count(), 23);
+
+        let mut batched = MerkleTree::<PoseidonAlgorithm>::build_empty_tree(6).unwrap();
+        assert_eq!(batched.insert_leaves(&leaves[..1]).unwrap().0, 0);
+        assert_eq!(batched.insert_leaves(&leaves[1..8]).unwrap().0, 1);
+        assert_eq!(batched.insert_leaves(&[]).unwrap().0, 8);
+        assert_eq!(batched.insert_leaves_iter(leaves[8..].iter().copied()), Ok((8, *sequential.root().unwrap())));
+        assert_eq!(batched.tree, sequential.tree);
+
+        let mut batched = MerkleTree::<Sha256Hasher>::build_empty_tree(11).unwrap();
+        let (first_index, root) = batched.insert_leaves_iter((0..999).map(get_leaf)).unwrap();
+        let mut sequential = MerkleTree::<Sha256Hasher>::build_empty_tree(11).unwrap();
+        build_mock_tree(&mut sequential, 999, get_leaf);
+        assert_eq!((first_index, &root), (0, sequential.root().unwrap()));
+    }
+
+    #[test]
+    fn test_insert_leaves_errors() {
+        let mut empty = MerkleTree::<PoseidonAlgorithm>::new();
+        assert_eq!(empty.insert_leaves(&[Fr::from(1u64)]), Err(MerkleTreeError::EmptyTree));
+
+        let mut merkle_tree = MerkleTree::<PoseidonAlgorithm>::build_empty_tree(3).unwrap();
+        merkle_tree.insert_leaves(&[Fr::from(1u64), Fr::from(2u64)]).unwrap();
+        let before = merkle_tree.clone();
+        let leaves = [Fr::from(3u64), Fr::from(4u64), Fr::from(5u64)];
+        assert_eq!(merkle_tree.insert_leaves(&leaves), Err(MerkleTreeError::TreeFull { capacity: 4 }));
+        // Nothing was inserted
+        assert_eq!(merkle_tree.tree, before.tree);
+        assert_eq!(merkle_tree.leaves_count(), 2);
+        assert_eq!(merkle_tree.insert_leaves(&leaves[..2]).unwrap().0, 2);
+    }
+
+    #[test]
+    fn test_update_leaf() {
+        let mut leaves: Vec<Fr> = (0..11u64).map(|leaf| Fr::from(leaf + 100)).collect();
+        let mut merkle_tree = MerkleTree::<PoseidonAlgorithm>::build_empty_tree(5).unwrap();
+        merkle_tree.insert_leaves(&leaves).unwrap();
+
+        for (index, new_value) in [(0usize, 7u64), (5, 8), (10, 9), (5, 10)] {
+            let old_value = leaves[index];
+            leaves[index] = Fr::from(new_value);
+            let expected_root = full_rebuild_root(&leaves, 5);
+            assert_eq!(merkle_tree.update_leaf(index, leaves[index]), Ok((old_value, expected_root)));
+            assert_eq!(merkle_tree.root(), Ok(&expected_root));
+            assert_eq!(merkle_tree.value(index), Ok(&leaves[index]));
+            let opening = merkle_tree.opening_orig(index).unwrap();
+            assert_eq!(merkle_tree.verify(opening.iter().collect(), index), Ok(true));
+        }
+        assert_eq!(merkle_tree.leaves_count(), 11);
+
+        // Only inserted leaves can be updated
+        assert_eq!(
+            merkle_tree.update_leaf(11, Fr::from(1u64)),
+            Err(MerkleTreeError::LeafNotFound { index: 11, leaves_count: 11 })
+        );
+        assert_eq!(
+            merkle_tree.update_leaf(16, Fr::from(1u64)),
+            Err(MerkleTreeError::IndexOutOfRange { index: 16, capacity: 16 })
+        );
+        assert_eq!(merkle_tree.root(), Ok(&full_rebuild_root(&leaves, 5)));
+
+        // Appending after updates still works
+        leaves.push(Fr::from(111u64));
+        merkle_tree.insert_leaf(leaves[11]).unwrap();
+        assert_eq!(merkle_tree.root(), Ok(&full_rebuild_root(&leaves, 5)));
+    }
+
+    #[test]
+    fn test_remove_leaf() {
+        let mut leaves: Vec<Fr> = (0..6u64).map(|leaf| Fr::from(leaf + 1)).collect();
+        let mut merkle_tree = MerkleTree::<PoseidonAlgorithm>::build_empty_tree(4).unwrap();
+        merkle_tree.insert_leaves(&leaves).unwrap();
+
+        let old_leaf = leaves[2];
+        leaves[2] = Fr::from(0u64);
+        assert_eq!(merkle_tree.remove_leaf(2), Ok((old_leaf, full_rebuild_root(&leaves, 4))));
+        assert_eq!(merkle_tree.value(2), Ok(&merkle_tree.empty_hashes[0]));
+
+        // Removed and never used slots hold the same value but are told apart
+        assert_eq!(merkle_tree.leaf_state(1), Ok(LeafState::Occupied));
+        assert_eq!(merkle_tree.leaf_state(2), Ok(LeafState::Removed));
+        assert_eq!(merkle_tree.leaf_state(6), Ok(LeafState::Empty));
+        assert_eq!(merkle_tree.value(2), merkle_tree.value(6));
+        assert_eq!(merkle_tree.removed_leaves().collect::<Vec<_>>(), vec![2]);
+
+        // The other leaves keep their index and the slot is not reused
+        assert_eq!(merkle_tree.leaves_count(), 6);
+        let opening = merkle_tree.opening_orig(3).unwrap();
+        assert_eq!(merkle_tree.verify(opening.iter().collect(), 3), Ok(true));
+        leaves.push(Fr::from(7u64));
+        assert_eq!(merkle_tree.insert_leaf(leaves[6]), Ok(6));
+        assert_eq!(merkle_tree.root(), Ok(&full_rebuild_root(&leaves, 4)));
+
+        assert_eq!(merkle_tree.remove_leaf(2), Err(MerkleTreeError::LeafRemoved(2)));
+        assert_eq!(merkle_tree.update_leaf(2, Fr::from(1u64)), Err(MerkleTreeError::LeafRemoved(2)));
+        assert_eq!(merkle_tree.remove_leaf(7), Err(MerkleTreeError::LeafNotFound { index: 7, leaves_count: 7 }));
+        assert_eq!(merkle_tree.leaf_state(8), Err(MerkleTreeError::IndexOutOfRange { index: 8, capacity: 8 }));
+
+        // The removed leaves survive serialization
+        let restored = round_trip(&merkle_tree, Compress::Yes);
+        assert_eq!(restored.leaf_state(2), Ok(LeafState::Removed));
+        assert_eq!(restored.root(), merkle_tree.root());
+    }
+
+    #[test]
+    fn test_root_history() {
+        let mut merkle_tree = MerkleTree::<PoseidonAlgorithm>::build_empty_tree(5).unwrap().with_root_history(4);
+        let empty_root = *merkle_tree.root().unwrap();
+        assert_eq!(merkle_tree.root_at(0), Some(&empty_root));
+
+        let mut roots = vec![empty_root];
+        for leaf in 0..6u64 {
+            merkle_tree.insert_leaf(Fr::from(leaf + 1)).unwrap();
+            roots.push(*merkle_tree.root().unwrap());
+        }
+
+        // Only the last 4 roots are kept
+        for (leaves_count, root) in roots.iter().enumerate() {
+            let kept = leaves_count >= 3;
+            assert_eq!(merkle_tree.is_known_root(root), kept);
+            assert_eq!(merkle_tree.root_at(leaves_count), if kept { Some(root) } else { None });
+        }
+        assert!(!merkle_tree.is_known_root(&Fr::from(1u64)));
+
+        // A batch records only its final root; updates record the root at the same size
+        let (_, batch_root) = merkle_tree.insert_leaves(&[Fr::from(7u64), Fr::from(8u64)]).unwrap();
+        assert_eq!(merkle_tree.root_at(8), Some(&batch_root));
+        assert_eq!(merkle_tree.root_at(7), None);
+        let (_, updated_root) = merkle_tree.update_leaf(0, Fr::from(100u64)).unwrap();
+        assert_eq!(merkle_tree.root_at(8), Some(&updated_root));
+        assert!(merkle_tree.is_known_root(&batch_root));
+        assert!(merkle_tree.is_known_root(&roots[5]));
+        assert!(!merkle_tree.is_known_root(&roots[4]));
+
+        let restored = round_trip(&merkle_tree, Compress::No);
+        assert_eq!(restored.root_history, merkle_tree.root_history);
+        assert_eq!(restored.root_history_capacity(), 4);
+
+        // Without a history only the current root is known
+        let mut merkle_tree = MerkleTree::<PoseidonAlgorithm>::build_empty_tree(5).unwrap();
+        merkle_tree.insert_leaf(Fr::from(1u64)).unwrap();
+        assert!(merkle_tree.is_known_root(&roots[1]));
+        assert!(!merkle_tree.is_known_root(&empty_root));
+        assert_eq!(merkle_tree.root_at(1), None);
+    }
+
+    #[test]
+    fn test_checkpoint_and_rewind() {
+        let mut merkle_tree = MerkleTree::<PoseidonAlgorithm>::build_empty_tree(5).unwrap().with_root_history(10);
+        merkle_tree.insert_leaves(&[Fr::from(1u64), Fr::from(2u64), Fr::from(3u64)]).unwrap();
+        merkle_tree.checkpoint(1).unwrap();
+        let at_1 = merkle_tree.clone();
+
+        merkle_tree.insert_leaf(Fr::from(4u64)).unwrap();
+        merkle_tree.update_leaf(0, Fr::from(10u64)).unwrap();
+        merkle_tree.checkpoint(2).unwrap();
+        let at_2 = merkle_tree.clone();
+
+        merkle_tree.update_leaf(0, Fr::from(20u64)).unwrap();
+        merkle_tree.remove_leaf(1).unwrap();
+        merkle_tree.insert_leaves(&[Fr::from(5u64), Fr::from(6u64)]).unwrap();
+        merkle_tree.update_leaf(4, Fr::from(50u64)).unwrap();
+        let reorged_root = *merkle_tree.root().unwrap();
+
+        assert_eq!(merkle_tree.rewind_to(2), Ok(*at_2.root().unwrap()));
+        assert_eq!(merkle_tree.tree, at_2.tree);
+        assert_eq!(merkle_tree.leaves_count(), 4);
+        assert_eq!(merkle_tree.leaf_state(1), Ok(LeafState::Occupied));
+        assert!(!merkle_tree.is_known_root(&reorged_root));
+
+        // Rewinding again to the same checkpoint is a no-op
+        assert_eq!(merkle_tree.rewind_to(2), Ok(*at_2.root().unwrap()));
+
+        // The tree keeps working after a rewind, and older checkpoints are still available
+        merkle_tree.insert_leaf(Fr::from(7u64)).unwrap();
+        assert_eq!(merkle_tree.rewind_to(1), Ok(*at_1.root().unwrap()));
+        assert_eq!(merkle_tree.tree, at_1.tree);
+        assert_eq!(merkle_tree.root_history, at_1.root_history);
+        assert_eq!(merkle_tree.checkpoint_ids().collect::<Vec<_>>(), vec![1]);
+        assert_eq!(merkle_tree.rewind_to(2), Err(MerkleTreeError::UnknownCheckpoint(2)));
+        assert_eq!(merkle_tree.checkpoint(1), Err(MerkleTreeError::CheckpointOutOfOrder { id: 1, last: 1 }));
+    }
+
+    #[test]
+    fn test_checkpoint_retention() {
+        let mut merkle_tree = MerkleTree::<PoseidonAlgorithm>::build_empty_tree(4).unwrap().with_max_checkpoints(2);
+        let mut roots = Vec::new();
+        for id in 0..4u64 {
+            merkle_tree.insert_leaf(Fr::from(id)).unwrap();
+            merkle_tree.checkpoint(id).unwrap();
+            roots.push(*merkle_tree.root().unwrap());
+        }
+        assert_eq!(merkle_tree.checkpoint_ids().collect::<Vec<_>>(), vec![2, 3]);
+        assert_eq!(merkle_tree.rewind_to(1), Err(MerkleTreeError::UnknownCheckpoint(1)));
+        assert_eq!(merkle_tree.rewind_to(2), Ok(roots[2]));
+        assert_eq!(merkle_tree.leaves_count(), 3);
+
+        // Nothing would be recorded, so the checkpoint fails instead of the later rewind
+        let mut merkle_tree = merkle_tree.with_max_checkpoints(0);
+        assert_eq!(merkle_tree.checkpoint_ids().count(), 0);
+        assert_eq!(merkle_tree.checkpoint(4), Err(MerkleTreeError::CheckpointsDisabled));
+        assert_eq!(merkle_tree.rewind_to(4), Err(MerkleTreeError::UnknownCheckpoint(4)));
+    }
+
+    #[test]
+    fn test_opening_at_past_tree_size() {
+        let mut merkle_tree = MerkleTree::<PoseidonAlgorithm>::build_empty_tree(5).unwrap();
+        let mut snapshots = vec![merkle_tree.clone()];
+        for leaf in 0..11u64 {
+            merkle_tree.insert_leaf(Fr::from(leaf + 3)).unwrap();
+            snapshots.push(merkle_tree.clone());
+        }
+
+        for (tree_size, snapshot) in snapshots.iter().enumerate() {
+            let root = *snapshot.root().unwrap();
+            assert_eq!(merkle_tree.historical_root(tree_size), Ok(root));
+            for leaf_index in 0..tree_size {
+                // Same opening as the one the tree gave back then
+                assert_eq!(merkle_tree.opening_at(leaf_index as u32, tree_size), snapshot.opening(leaf_index as u32));
+                let proof = merkle_tree.proof_at(leaf_index, tree_size).unwrap();
+                assert!(crate::merkle_proof::verify(&root, &proof));
+            }
+        }
+
+        assert_eq!(
+            merkle_tree.opening_at(5, 5),
+            Err(MerkleTreeError::LeafNotFound { index: 5, leaves_count: 5 })
+        );
+        assert_eq!(merkle_tree.proof_at(0, 12), Err(MerkleTreeError::InvalidTreeSize { size: 12, max: 11 }));
+        assert_eq!(merkle_tree.proof_at(0, 0), Err(MerkleTreeError::InvalidTreeSize { size: 0, max: 11 }));
+        assert_eq!(merkle_tree.historical_root(12), Err(MerkleTreeError::InvalidTreeSize { size: 12, max: 11 }));
+        assert_eq!(MerkleTree::<PoseidonAlgorithm>::new().historical_root(0), Err(MerkleTreeError::EmptyTree));
+    }
+
+    fn round_trip<T: CanonicalSerialize + CanonicalDeserialize>(value: &T, compress: Compress) -> T {
+        let mut bytes = Vec::new();
+        value.serialize_with_mode(&mut bytes, compress).unwrap();
+        assert_eq!(bytes.len(), value.serialized_size(compress));
+        T::deserialize_with_mode(bytes.as_slice(), compress, Validate::Yes).unwrap()
+    }
+
+    #[test]
+    fn test_serialize_tree_opening_and_root() {
+        let mut merkle_tree = MerkleTree::<PoseidonAlgorithm>::build_empty_tree(5).unwrap();
+        for leaf in 0..9u64 {
+            merkle_tree.insert_leaf(Fr::from(leaf)).unwrap();
+        }
+
+        for compress in [Compress::Yes, Compress::No] {
+            let mut restored = round_trip(&merkle_tree, compress);
+            assert_eq!(restored.tree, merkle_tree.tree);
+            assert_eq!(restored.leaves_count(), 9);
+            assert_eq!(restored.empty_hashes, merkle_tree.empty_hashes);
+
+            // The restored tree keeps working
+            restored.insert_leaf(Fr::from(9u64)).unwrap();
+            let opening = restored.opening_orig(9).unwrap();
+            assert_eq!(restored.verify(opening.iter().collect(), 9), Ok(true));
+
+            let opening: Opening<PoseidonAlgorithm> = merkle_tree.opening(3).unwrap();
+            assert_eq!(round_trip(&opening, compress), opening);
+
+            let root = *merkle_tree.root().unwrap();
+            assert_eq!(round_trip(&root, compress), root);
+        }
+
+        // An empty tree round trips too
+        let empty = MerkleTree::<Sha256Hasher>::new();
+        assert_eq!(round_trip(&empty, Compress::Yes).depth(), 0);
+    }
+
+    #[test]
+    fn test_deserialize_rejects_malformed_tree() {
+        let mut merkle_tree = MerkleTree::<Sha256Hasher>::build_empty_tree(4).unwrap();
+        build_mock_tree(&mut merkle_tree, 3, get_leaf);
+
+        let mut bad_shape = merkle_tree.clone();
+        bad_shape.tree[1].pop();
+        let mut bytes = Vec::new();
+        bad_shape.serialize_compressed(&mut bytes).unwrap();
+        assert!(MerkleTree::<Sha256Hasher>::deserialize_compressed(bytes.as_slice()).is_err());
+
+        let mut bad_count = merkle_tree.clone();
+        bad_count.leaves_count = 9;
+        let mut bytes = Vec::new();
+        bad_count.serialize_uncompressed(&mut bytes).unwrap();
+        assert!(MerkleTree::<Sha256Hasher>::deserialize_uncompressed(bytes.as_slice()).is_err());
+        assert!(MerkleTree::<Sha256Hasher>::deserialize_uncompressed_unchecked(bytes.as_slice()).is_ok());
+
+        // A removed leaf must hold the empty leaf
+        let mut bad_removed = merkle_tree.clone();
+        bad_removed.removed.insert(1);
+        let mut bytes = Vec::new();
+        bad_removed.serialize_compressed(&mut bytes).unwrap();
+        assert!(MerkleTree::<Sha256Hasher>::deserialize_compressed(bytes.as_slice()).is_err());
+    }
+
+    #[test]
+    fn test_errors_instead_of_panics() {
+        let mut empty = MerkleTree::<PoseidonAlgorithm>::new();
+        assert_eq!(empty.insert_leaf(Fr::from(1u64)), Err(MerkleTreeError::EmptyTree));
+        assert_eq!(empty.root(), Err(MerkleTreeError::EmptyTree));
+        assert_eq!(empty.value(0), Err(MerkleTreeError::EmptyTree));
+        assert_eq!(empty.opening(0), Err(MerkleTreeError::EmptyTree));
+        assert!(MerkleTree::<PoseidonAlgorithm>::build_empty_tree(0).is_err());
+        assert_eq!(
+            MerkleTree::<PoseidonAlgorithm>::build_empty_tree(64).unwrap_err(),
+            MerkleTreeError::InvalidDepth(64)
+        );
+        assert_eq!(
+            MerkleTree::<PoseidonAlgorithm>::build_empty_tree(65).unwrap_err(),
+            MerkleTreeError::InvalidDepth(65)
+        );
+        // Fits in a usize but not in memory
+        assert_eq!(
+            MerkleTree::<PoseidonAlgorithm>::build_empty_tree(50).unwrap_err(),
+            MerkleTreeError::InvalidDepth(50)
+        );
+
+        let mut merkle_tree = MerkleTree::<PoseidonAlgorithm>::build_empty_tree(2).unwrap();
+        assert_eq!(merkle_tree.insert_leaf(Fr::from(1u64)), Ok(0));
+        assert_eq!(merkle_tree.insert_leaf(Fr::from(2u64)), Ok(1));
+        assert_eq!(merkle_tree.insert_leaf(Fr::from(3u64)), Err(MerkleTreeError::TreeFull { capacity: 2 }));
+        assert_eq!(merkle_tree.leaves_count(), 2);
+
+        let out_of_range = MerkleTreeError::IndexOutOfRange { index: 2, capacity: 2 };
+        assert_eq!(merkle_tree.value(2), Err(out_of_range.clone()));
+        assert_eq!(merkle_tree.opening_orig(2), Err(out_of_range.clone()));
+        assert_eq!(merkle_tree.opening(2), Err(out_of_range.clone()));
+        assert_eq!(merkle_tree.verify(vec![], 2), Err(out_of_range));
+        assert_eq!(
+            merkle_tree.verify(vec![], 0),
+            Err(MerkleTreeError::InvalidProofLength { expected: 1, found: 0 })
+        );
+    }
+
+    #[test]
+    fn test_add_one_leaf() {
+        let mut merkle_tree = MerkleTree::<Sha256Hasher>::build_empty_tree(1).unwrap();
+        // Assert the merkle tree must be empty before inserting a leaf
+        assert_eq!(merkle_tree.leaves_count(), 0);
+        // Let's build a merkle tree with one node
+        merkle_tree.insert_leaf(Sha256Hasher::hash_leaf_data("hello".as_bytes())).unwrap();
+        // the leaf must be the root in this case
+        assert_eq!(merkle_tree.root(), merkle_tree.value(0));
+        assert_eq!(merkle_tree.leaves_count(), 1)
+    }
+
+    #[test]
+    fn test_add_two_leaves() {
+        let mut merkle_tree = MerkleTree::<Sha256Hasher>::build_empty_tree(2).unwrap();
+        // Assert the merkle tree must be empty before inserting a leaf
+        assert_eq!(merkle_tree.leaves_count(), 0);
+        // Build a mock merkle tree with 2 leaves
+        build_mock_tree(&mut merkle_tree, 2, get_leaf);
+
+        // The tree height is expected to be 2
+        assert_eq!(merkle_tree.depth(), 2);
+        assert_eq!(merkle_tree.leaves_count(), 2);
+
+        // Check if the input leaf values (hashes) are stored correctly in the tree
+        assert_eq!(merkle_tree.value(0), Ok(&get_leaf(0)));
+        assert_eq!(merkle_tree.value(1), Ok(&get_leaf(1)));
+        assert_eq!(merkle_tree.root(), Ok(&Sha256Hasher::concat_and_hash(&get_leaf(0), &get_leaf(1))));
+    }
+
+    #[test]
+    fn test_add_three_leaves() {
+        let mut merkle_tree = MerkleTree::<Sha256Hasher>::build_empty_tree(3).unwrap();
+
+        // // Assert the merkle tree must be empty before inserting a leaf
+        assert_eq!(merkle_tree.leaves_count(), 0);
+
+        // Build a mock merkle tree with 3 leaves
+        build_mock_tree(&mut merkle_tree, 3, get_leaf);
+
+        assert_eq!(merkle_tree.leaves_count(), 3);
+        // The expected tree height should 3
+        //     root
+        //     /  \
+        //   i_0  i_1
+        //  / \   / \
+        //  0 1  2  empty
+        assert_eq!(merkle_tree.depth(), 3);
+
+        // Check if the input leaf values (hashes) are stored correctly in the tree
+        assert_eq!(merkle_tree.value(0), Ok(&get_leaf(0)));
+        assert_eq!(merkle_tree.value(1), Ok(&get_leaf(1)));
+        assert_eq!(merkle_tree.value(2), Ok(&get_leaf(2)));
+
+        // get merkle proof of the leaf at index 1
+        let opening = merkle_tree.opening_orig(1).unwrap();
+        assert_eq!(merkle_tree.verify(opening.iter().collect(), 1), Ok(true));
+    }
+
+    #[test]
+    fn test_add_five_leaves() {
+        let mut merkle_tree = MerkleTree::<Sha256Hasher>::build_empty_tree(4).unwrap();
+
+        // // Assert the merkle tree must be empty before inserting a leaf
+        assert_eq!(merkle_tree.leaves_count(), 0);
+
+        // Build a mock merkle tree with 5 leaves
+        build_mock_tree(&mut merkle_tree, 5, get_leaf);
+
+        assert_eq!(merkle_tree.leaves_count(), 5);
+        // The expected tree height should 4
+        //          root
+        //       /       \
+        //     i_3       i_4
+        //    /   \     /  \
+        //  i_0  i_1  i_2 empty
+        //  / \  / \  / \
+        //  0 1  2 3  4 empty
+        assert_eq!(merkle_tree.depth(), 4);
+
+        // Check if the input leaf values (hashes) are stored correctly in the tree
+        assert_eq!(merkle_tree.value(0), Ok(&get_leaf(0)));
+        assert_eq!(merkle_tree.value(1), Ok(&get_leaf(1)));
+        assert_eq!(merkle_tree.value(4), Ok(&get_leaf(4)));
+
+        // get merkle proof of the leaf at index 4
+        let opening = merkle_tree.opening_orig(4).unwrap();
+        assert_eq!(merkle_tree.verify(opening.iter().collect(), 4), Ok(true));
+    }
+
+    #[test]
+    fn test_add_999_leaves() {
+        let mut merkle_tree = MerkleTree::<Sha256Hasher>::build_empty_tree(11).unwrap();
+
+        // // Assert the merkle tree must be empty before inserting a leaf
+        assert_eq!(merkle_tree.leaves_count(), 0);
+
+        // Build a mock merkle tree with 999 leaves
+        build_mock_tree(&mut merkle_tree, 999, get_leaf);
+
+        // Check if the input leaf values (hashes) are stored correctly in the tree
+        assert_eq!(merkle_tree.value(0), Ok(&get_leaf(0)));
+        assert_eq!(merkle_tree.value(1), Ok(&get_leaf(1)));
+        assert_eq!(merkle_tree.value(4), Ok(&get_leaf(4)));
+        assert_eq!(merkle_tree.value(101), Ok(&get_leaf(101)));
+        assert_eq!(merkle_tree.value(567), Ok(&get_leaf(567)));
+        assert_eq!(merkle_tree.value(789), Ok(&get_leaf(789)));
+
+        // get merkle proof of the leaf at index 579
+        let opening = merkle_tree.opening_orig(579).unwrap();
+        assert_eq!(merkle_tree.verify(opening.iter().collect(), 579), Ok(true));
+    }
+
+    #[test]
+    fn test_merkle_proof_1176_leaves() {
+        let mut merkle_tree = MerkleTree::<Sha256Hasher>::build_empty_tree(12).unwrap();
+
+        // Assert the merkle tree must be empty before inserting a leaf
+        assert_eq!(merkle_tree.leaves_count(), 0);
+
+        // Build a mock merkle tree with 1176 leaves
+        build_mock_tree(&mut merkle_tree, 1176, get_leaf);
+
+        assert_eq!(merkle_tree.leaves_count(), 1176);
+        // get merkle proof of the leaf at index 999
+        let opening = merkle_tree.opening_orig(999).unwrap();
+        assert_eq!(merkle_tree.verify(opening.iter().collect(), 999), Ok(true));
+
+        // A tampered opening must not verify
+        let mut tampered = opening.clone();
+        tampered[0] = get_leaf(0);
+        assert_eq!(merkle_tree.verify(tampered.iter().collect(), 999), Ok(false));
+    }
+}
@@ -0,0 +1,135 @@
+use crate::error::MerkleTreeError;
+use crate::frontier::MerkleFrontier;
+use crate::hasher::Hasher;
+use crate::incremental_merkle_tree::Opening;
+use crate::merkle_proof::MerkleProof;
+use crate::poseidon::PoseidonAlgorithm;
+
+// Authentication path of one leaf that stays current as later leaves are appended, without keeping the tree
+// (as zcash's IncrementalWitness). Along with the path it keeps the frontier of the tree: every append yields the
+// nodes on the path of the new leaf, and the one that is a right sibling of our path replaces the old value.
+// Siblings on the left of the path never change. Created with MerkleTree::witness or MerkleFrontier::witness.
+#[derive(Debug, Clone)]
+pub struct IncrementalWitness<H = PoseidonAlgorithm>
+where
+    H: Hasher,
+{
+    leaf_index: usize,
+    leaf: H::Hash,
+    // One sibling per level from the leaves up to (not including) the root, as in MerkleProof.
+    siblings: Vec<H::Hash>,
+    frontier: MerkleFrontier<H>,
+}
+
+impl<H> IncrementalWitness<H>
+where
+    H: Hasher,
+{
+    pub(crate) fn new(leaf_index: usize, leaf: H::Hash, siblings: Vec<H::Hash>, frontier: MerkleFrontier<H>) -> Self {
+        Self {
+            leaf_index,
+            leaf,
+            siblings,
+            frontier,
+        }
+    }
+
+    // Appends a leaf to the tree followed by the witness and updates the path, in O(depth).
+    // Returns the index of the new leaf.
+    pub fn append(&mut self, leaf: H::Hash) -> Result<usize, MerkleTreeError> {
+        let leaf_index = self.leaf_index;
+        let new_index = self.frontier.leaves_count();
+        let siblings = &mut self.siblings;
+        self.frontier.insert_leaf_with(leaf, |level, node| {
+            // The new leaf comes after ours, so when their nodes are siblings the new one is on the right
+            if new_index >> level == (leaf_index >> level) ^ 1 {
+                siblings[level] = *node;
+            }
+        })
+    }
+
+    pub fn leaf_index(&self) -> usize {
+        self.leaf_index
+    }
+
+    pub fn leaf(&self) -> &H::Hash {
+        &self.leaf
+    }
+
+    // Root of the tree followed by the witness, with all leaves appended so far.
+    pub fn root(&self) -> &H::Hash {
+        self.frontier.root()
+    }
+
+    pub fn leaves_count(&self) -> usize {
+        self.frontier.leaves_count()
+    }
+
+    // Proof of the leaf against the latest root, which can be checked with merkle_proof::verify.
+    pub fn proof(&self) -> MerkleProof<H> {
+        MerkleProof::new(self.leaf_index, self.leaf, self.siblings.clone())
+    }
+
+    // Opening of the leaf against the latest root, in the format of MerkleTree::opening.
+    pub fn opening(&self) -> Opening<H> {
+        self.proof().to_opening()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::incremental_merkle_tree::MerkleTree;
+    use crate::merkle_proof::verify;
+    use ark_bn254::Fr;
+
+    #[test]
+    fn test_witness_follows_appends() {
+        let mut merkle_tree = MerkleTree::<PoseidonAlgorithm>::build_empty_tree(5).unwrap();
+        merkle_tree.insert_leaves(&(0..5u64).map(Fr::from).collect::<Vec<_>>()).unwrap();
+        let mut witnesses: Vec<IncrementalWitness<PoseidonAlgorithm>> =
+            [0usize, 3, 4].iter().map(|&index| merkle_tree.witness(index).unwrap()).collect();
+
+        for leaf in 5..16u64 {
+            let index = merkle_tree.insert_leaf(Fr::from(leaf)).unwrap();
+            for witness in witnesses.iter_mut() {
+                assert_eq!(witness.append(Fr::from(leaf)), Ok(index));
+                assert_eq!(Ok(witness.root()), merkle_tree.root());
+                assert_eq!(Ok(witness.opening()), merkle_tree.opening(witness.leaf_index() as u32));
+                assert!(verify(merkle_tree.root().unwrap(), &witness.proof()));
+            }
+        }
+
+        let witness = &mut witnesses[0];
+        assert_eq!(witness.leaves_count(), 16);
+        assert_eq!(witness.append(Fr::from(16u64)), Err(MerkleTreeError::TreeFull { capacity: 16 }));
+    }
+
+    #[test]
+    fn test_witness_from_frontier() {
+        let mut merkle_tree = MerkleTree::<PoseidonAlgorithm>::build_empty_tree(4).unwrap();
+        let mut frontier = MerkleFrontier::<PoseidonAlgorithm>::new(4).unwrap();
+        assert!(frontier.witness().is_err());
+
+        for leaf in 0..3u64 {
+            merkle_tree.insert_leaf(Fr::from(leaf)).unwrap();
+            frontier.insert_leaf(Fr::from(leaf)).unwrap();
+        }
+        // Witnesses of a left and of a right child
+        let mut left = frontier.witness().unwrap();
+        merkle_tree.insert_leaf(Fr::from(3u64)).unwrap();
+        frontier.insert_leaf(Fr::from(3u64)).unwrap();
+        left.append(Fr::from(3u64)).unwrap();
+        let mut right = frontier.witness().unwrap();
+        assert_eq!((left.leaf_index(), right.leaf_index()), (2, 3));
+        assert_eq!(right.leaf(), &Fr::from(3u64));
+
+        for leaf in 4..8u64 {
+            merkle_tree.insert_leaf(Fr::from(leaf)).unwrap();
+            left.append(Fr::from(leaf)).unwrap();
+            right.append(Fr::from(leaf)).unwrap();
+        }
+        assert_eq!(Ok(left.opening()), merkle_tree.opening(2));
+        assert_eq!(Ok(right.opening()), merkle_tree.opening(3));
+    }
+}
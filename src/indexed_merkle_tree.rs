@@ -0,0 +1,251 @@
+use crate::error::MerkleTreeError;
+use crate::hasher::Hasher;
+use crate::incremental_merkle_tree::MerkleTree;
+use crate::merkle_proof::{self, MerkleProof};
+use crate::poseidon::PoseidonAlgorithm;
+use ark_bn254::Fr;
+use ark_ff::Zero;
+use std::collections::BTreeMap;
+
+// Leaf of an IndexedMerkleTree: the leaves form a linked list sorted by value, where next_index and next_value
+// point to the leaf with the next larger value. The leaf with the largest value points to (0, 0).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IndexedLeaf {
+    pub value: Fr,
+    pub next_index: usize,
+    pub next_value: Fr,
+}
+
+impl IndexedLeaf {
+    pub fn hash<H: Hasher<Hash = Fr>>(&self) -> Fr {
+        H::hash([self.value, Fr::from(self.next_index as u64), self.next_value])
+    }
+
+    // Whether value is strictly between this leaf and the next one, i.e. is not in the tree if this leaf is.
+    pub fn is_low_leaf_of(&self, value: &Fr) -> bool {
+        let is_last = self.next_index == 0 && self.next_value.is_zero();
+        self.value < *value && (is_last || *value < self.next_value)
+    }
+}
+
+// Indexed Merkle tree as in Aztec's nullifier tree: a set of values (compared as integers) in which a value is
+// shown to be absent by opening its "low leaf", the leaf with the largest smaller value, whose next value is larger.
+// Leaves are appended to a MerkleTree in insertion order, hashed with H([value, next_index, next_value]).
+// Index 0 holds the leaf of value 0, so 0 is always in the set.
+#[derive(Debug, Clone)]
+pub struct IndexedMerkleTree<H = PoseidonAlgorithm>
+where
+    H: Hasher<Hash = Fr>,
+{
+    tree: MerkleTree<H>,
+    leaves: Vec<IndexedLeaf>,
+    // Index of the leaf of each value.
+    indices: BTreeMap<Fr, usize>,
+}
+
+// Witness that value is not in the tree: the opening of its low leaf.
+#[derive(Debug, Clone)]
+pub struct NonMembershipWitness<H = PoseidonAlgorithm>
+where
+    H: Hasher<Hash = Fr>,
+{
+    pub value: Fr,
+    pub low_leaf: IndexedLeaf,
+    pub proof: MerkleProof<H>,
+}
+
+impl<H> PartialEq for NonMembershipWitness<H>
+where
+    H: Hasher<Hash = Fr>,
+{
+    fn eq(&self, other: &Self) -> bool {
+        self.value == other.value && self.low_leaf == other.low_leaf && self.proof == other.proof
+    }
+}
+
+impl<H> Eq for NonMembershipWitness<H> where H: Hasher<Hash = Fr> {}
+
+impl<H> IndexedMerkleTree<H>
+where
+    H: Hasher<Hash = Fr>,
+{
+    // Tree with `depth` levels (including the root) that contains the value 0.
+    pub fn new(depth: u32) -> Result<Self, MerkleTreeError> {
+        let mut indexed_tree = Self {
+            tree: MerkleTree::build_empty_tree(depth)?,
+            leaves: Vec::new(),
+            indices: BTreeMap::new(),
+        };
+        let first_leaf = IndexedLeaf {
+            value: Fr::zero(),
+            next_index: 0,
+            next_value: Fr::zero(),
+        };
+        indexed_tree.tree.insert_leaf(first_leaf.hash::<H>())?;
+        indexed_tree.leaves.push(first_leaf);
+        indexed_tree.indices.insert(Fr::zero(), 0);
+        Ok(indexed_tree)
+    }
+
+    // Appends a leaf for value and links it after its low leaf, which is updated. Returns the index of the new leaf.
+    pub fn insert(&mut self, value: Fr) -> Result<usize, MerkleTreeError> {
+        let low_index = self.low_leaf_index(&value)?;
+        if self.tree.leaves_count() >= self.tree.capacity() {
+            return Err(MerkleTreeError::TreeFull { capacity: self.tree.capacity() });
+        }
+
+        let new_index = self.tree.leaves_count();
+        let low_leaf = &mut self.leaves[low_index];
+        let new_leaf = IndexedLeaf {
+            value,
+            next_index: low_leaf.next_index,
+            next_value: low_leaf.next_value,
+        };
+        low_leaf.next_index = new_index;
+        low_leaf.next_value = value;
+
+        let low_leaf_hash = low_leaf.hash::<H>();
+        self.tree.update_leaf(low_index, low_leaf_hash)?;
+        self.tree.insert_leaf(new_leaf.hash::<H>())?;
+        self.leaves.push(new_leaf);
+        self.indices.insert(value, new_index);
+        Ok(new_index)
+    }
+
+    pub fn contains(&self, value: &Fr) -> bool {
+        self.indices.contains_key(value)
+    }
+
+    pub fn leaf(&self, leaf_index: usize) -> Option<&IndexedLeaf> {
+        self.leaves.get(leaf_index)
+    }
+
+    pub fn root(&self) -> &Fr {
+        self.tree.root().expect("the tree is built in new")
+    }
+
+    pub fn leaves_count(&self) -> usize {
+        self.tree.leaves_count()
+    }
+
+    // Opening of the leaf of a value that is in the tree, e.g. to prove membership.
+    pub fn membership_proof(&self, value: &Fr) -> Option<MerkleProof<H>> {
+        let leaf_index = *self.indices.get(value)?;
+        self.tree.proof(leaf_index).ok()
+    }
+
+    // Witness that value is not in the tree.
+    pub fn non_membership_witness(&self, value: &Fr) -> Result<NonMembershipWitness<H>, MerkleTreeError> {
+        let low_index = self.low_leaf_index(value)?;
+        Ok(NonMembershipWitness {
+            value: *value,
+            low_leaf: self.leaves[low_index],
+            proof: self.tree.proof(low_index)?,
+        })
+    }
+
+    // Index of the leaf with the largest value below `value`, or ValueExists if value is in the tree.
+    fn low_leaf_index(&self, value: &Fr) -> Result<usize, MerkleTreeError> {
+        if let Some(&index) = self.indices.get(value) {
+            return Err(MerkleTreeError::ValueExists { index });
+        }
+        // 0 is always in the tree and below any other value
+        Ok(*self.indices.range(..*value).next_back().expect("0 is in the tree").1)
+    }
+}
+
+impl<H> NonMembershipWitness<H>
+where
+    H: Hasher<Hash = Fr>,
+{
+    // Checks that the low leaf is in the tree with this root and that value falls between it and the next leaf.
+    pub fn verify(&self, root: &Fr) -> bool {
+        self.low_leaf.is_low_leaf_of(&self.value)
+            && self.proof.leaf == self.low_leaf.hash::<H>()
+            && merkle_proof::verify(root, &self.proof)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert_keeps_sorted_list() {
+        let mut indexed_tree = IndexedMerkleTree::<PoseidonAlgorithm>::new(4).unwrap();
+        for value in [30u64, 10, 20, 50] {
+            indexed_tree.insert(Fr::from(value)).unwrap();
+        }
+        assert_eq!(indexed_tree.leaves_count(), 5);
+
+        // Following the links from the leaf of 0 visits the values in order
+        let mut values = Vec::new();
+        let mut leaf = indexed_tree.leaf(0).unwrap();
+        while leaf.next_index != 0 {
+            values.push(leaf.next_value);
+            leaf = indexed_tree.leaf(leaf.next_index).unwrap();
+        }
+        assert_eq!(values, [10u64, 20, 30, 50].map(Fr::from).to_vec());
+        assert_eq!(leaf.next_value, Fr::zero());
+
+        // The MerkleTree holds the hashes of the leaves
+        let mut merkle_tree = MerkleTree::<PoseidonAlgorithm>::build_empty_tree(4).unwrap();
+        for leaf_index in 0..5 {
+            merkle_tree.insert_leaf(indexed_tree.leaf(leaf_index).unwrap().hash::<PoseidonAlgorithm>()).unwrap();
+        }
+        assert_eq!(merkle_tree.root(), Ok(indexed_tree.root()));
+
+        let proof = indexed_tree.membership_proof(&Fr::from(20u64)).unwrap();
+        assert_eq!(proof.leaf_index, 3);
+        assert!(merkle_proof::verify(indexed_tree.root(), &proof));
+        assert!(indexed_tree.membership_proof(&Fr::from(25u64)).is_none());
+
+        assert_eq!(indexed_tree.insert(Fr::from(20u64)), Err(MerkleTreeError::ValueExists { index: 3 }));
+        assert_eq!(indexed_tree.insert(Fr::zero()), Err(MerkleTreeError::ValueExists { index: 0 }));
+    }
+
+    #[test]
+    fn test_non_membership_witness() {
+        let mut indexed_tree = IndexedMerkleTree::<PoseidonAlgorithm>::new(5).unwrap();
+        for value in [100u64, 300, 200] {
+            indexed_tree.insert(Fr::from(value)).unwrap();
+        }
+        let root = *indexed_tree.root();
+
+        // Below the smallest value, between two values and above the largest one
+        for (value, low_value) in [(1u64, 0u64), (150, 100), (250, 200), (1000, 300)] {
+            let witness = indexed_tree.non_membership_witness(&Fr::from(value)).unwrap();
+            assert_eq!(witness.low_leaf.value, Fr::from(low_value));
+            assert!(witness.verify(&root));
+        }
+        assert_eq!(
+            indexed_tree.non_membership_witness(&Fr::from(200u64)),
+            Err(MerkleTreeError::ValueExists { index: 3 })
+        );
+
+        // A witness for one value does not prove anything about another one
+        let witness = indexed_tree.non_membership_witness(&Fr::from(150u64)).unwrap();
+        let other_value = NonMembershipWitness { value: Fr::from(250u64), ..witness.clone() };
+        assert!(!other_value.verify(&root));
+        let present_value = NonMembershipWitness { value: Fr::from(200u64), ..witness.clone() };
+        assert!(!present_value.verify(&root));
+
+        // Nor about a value that was inserted since
+        indexed_tree.insert(Fr::from(150u64)).unwrap();
+        assert!(!witness.verify(indexed_tree.root()));
+        let mut stale_leaf = witness.clone();
+        stale_leaf.proof = indexed_tree.membership_proof(&Fr::from(100u64)).unwrap();
+        assert!(!stale_leaf.verify(indexed_tree.root()));
+    }
+
+    #[test]
+    fn test_full_tree() {
+        let mut indexed_tree = IndexedMerkleTree::<PoseidonAlgorithm>::new(2).unwrap();
+        indexed_tree.insert(Fr::from(5u64)).unwrap();
+        let root = *indexed_tree.root();
+        assert_eq!(indexed_tree.insert(Fr::from(6u64)), Err(MerkleTreeError::TreeFull { capacity: 2 }));
+        // Nothing changed
+        assert_eq!(*indexed_tree.root(), root);
+        assert_eq!(indexed_tree.leaf(1).unwrap().next_index, 0);
+    }
+}
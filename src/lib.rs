@@ -1,5 +1,18 @@
-
 pub mod incremental_merkle_tree;
 pub mod merkle_mountain_range_tree;
+pub mod frontier;
+pub mod incremental_witness;
+pub mod pruned_merkle_tree;
+pub mod sparse_merkle_tree;
+pub mod indexed_merkle_tree;
+pub mod nary_merkle_tree;
+pub mod merkle_proof;
+pub mod error;
 pub mod hasher;
-pub mod poseidon;
\ No newline at end of file
+pub mod poseidon;
+pub mod poseidon_gadget;
+pub mod merkle_tree_gadget;
+pub mod sha256;
+
+#[cfg(test)]
+mod test_utils;
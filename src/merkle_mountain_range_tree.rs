@@ -1 +1,364 @@
+use crate::hasher::Hasher;
+use crate::poseidon::PoseidonAlgorithm;
 
+// Merkle Mountain Range: an append-only list of perfect binary trees ("mountains") with no fixed depth.
+// Nodes are stored in post-order, so a node's children always come before it. Positions are 0-based, e.g. for 4 leaves:
+//
+//        6
+//      /   \
+//     2     5
+//    / \   / \
+//   0   1 3   4
+//
+// The root is obtained by "bagging" the peaks from right to left: root = H(p_0, H(p_1, ... H(p_{k-2}, p_{k-1}))).
+#[derive(Debug, Clone)]
+pub struct MerkleMountainRange<H = PoseidonAlgorithm>
+where
+    H: Hasher,
+{
+    pub nodes: Vec<H::Hash>,
+    pub leaves_count: usize,
+}
+
+// Inclusion proof of a leaf against the MMR of a given size.
+#[derive(Debug, Clone)]
+pub struct MmrProof<H = PoseidonAlgorithm>
+where
+    H: Hasher,
+{
+    pub mmr_size: usize,
+    pub leaf_index: usize,
+    // Siblings from the leaf up to (not including) its peak.
+    pub siblings: Vec<H::Hash>,
+    // All other peaks from left to right, excluding the one containing the leaf.
+    pub peaks: Vec<H::Hash>,
+}
+
+impl<H> Default for MerkleMountainRange<H>
+where
+    H: Hasher,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<H> MerkleMountainRange<H>
+where
+    H: Hasher,
+{
+    pub fn new() -> Self {
+        Self {
+            nodes: Vec::new(),
+            leaves_count: 0,
+        }
+    }
+
+    // Appends a leaf and merges equal-height mountains. Returns the position of the leaf.
+    pub fn append(&mut self, leaf: H::Hash) -> usize {
+        let leaf_pos = self.nodes.len();
+        self.nodes.push(leaf);
+
+        // Keep merging while the next position is a parent of the last node.
+        let mut height = 0;
+        while pos_height(self.nodes.len()) > height {
+            let right = self.nodes[self.nodes.len() - 1];
+            let left = self.nodes[self.nodes.len() - (2 << height)];
+            self.nodes.push(H::concat_and_hash(&left, &right));
+            height += 1;
+        }
+
+        self.leaves_count += 1;
+        leaf_pos
+    }
+
+    pub fn leaves_count(&self) -> usize {
+        self.leaves_count
+    }
+
+    // Total number of nodes (leaves and internal nodes).
+    pub fn mmr_size(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn value(&self, leaf_index: usize) -> Option<&H::Hash> {
+        if leaf_index >= self.leaves_count {
+            return None;
+        }
+        self.nodes.get(leaf_index_to_pos(leaf_index)?)
+    }
+
+    pub fn peaks(&self) -> Vec<H::Hash> {
+        self.peaks_at(self.nodes.len()).unwrap_or_default()
+    }
+
+    // Peaks of the MMR as it was when it had `mmr_size` nodes.
+    pub fn peaks_at(&self, mmr_size: usize) -> Option<Vec<H::Hash>> {
+        if mmr_size > self.nodes.len() || !is_valid_mmr_size(mmr_size) {
+            return None;
+        }
+        Some(peak_positions(mmr_size).into_iter().map(|pos| self.nodes[pos]).collect())
+    }
+
+    pub fn root(&self) -> Option<H::Hash> {
+        self.root_at(self.nodes.len())
+    }
+
+    // Root of the MMR as it was when it had `mmr_size` nodes.
+    pub fn root_at(&self, mmr_size: usize) -> Option<H::Hash> {
+        bag_peaks::<H>(&self.peaks_at(mmr_size)?)
+    }
+
+    // Inclusion proof of the leaf at `leaf_index` against the root of the MMR with `mmr_size` nodes.
+    pub fn opening(&self, leaf_index: usize, mmr_size: usize) -> Option<MmrProof<H>> {
+        if mmr_size > self.nodes.len() || !is_valid_mmr_size(mmr_size) {
+            return None;
+        }
+        let leaf_pos = leaf_index_to_pos(leaf_index)?;
+        if leaf_pos >= mmr_size {
+            return None;
+        }
+
+        let peaks = peak_positions(mmr_size);
+        let peak_idx = peaks.iter().position(|&peak| peak >= leaf_pos)?;
+
+        let mut siblings = Vec::new();
+        let mut pos = leaf_pos;
+        let mut height = 0;
+        while pos != peaks[peak_idx] {
+            if pos_height(pos + 1) > height {
+                // Right child: the parent comes right after it.
+                siblings.push(self.nodes[pos + 1 - (2 << height)]);
+                pos += 1;
+            } else {
+                // Left child: the sibling subtree comes right after it.
+                siblings.push(self.nodes[pos + (2 << height) - 1]);
+                pos += 2 << height;
+            }
+            height += 1;
+        }
+
+        let other_peaks = peaks
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| *i != peak_idx)
+            .map(|(_, &peak)| self.nodes[peak])
+            .collect();
+
+        Some(MmrProof {
+            mmr_size,
+            leaf_index,
+            siblings,
+            peaks: other_peaks,
+        })
+    }
+}
+
+impl<H> MmrProof<H>
+where
+    H: Hasher,
+{
+    // Checks that `leaf` is at `leaf_index` in the MMR with `mmr_size` nodes whose root is `root`.
+    pub fn verify(&self, root: &H::Hash, leaf: &H::Hash) -> bool {
+        if !is_valid_mmr_size(self.mmr_size) {
+            return false;
+        }
+        // The index comes from the proof: it must not wrap around to the position of another leaf
+        let leaf_pos = match leaf_index_to_pos(self.leaf_index) {
+            Some(pos) if pos < self.mmr_size => pos,
+            _ => return false,
+        };
+
+        let peaks = peak_positions(self.mmr_size);
+        let peak_idx = match peaks.iter().position(|&peak| peak >= leaf_pos) {
+            Some(idx) => idx,
+            None => return false,
+        };
+        if self.peaks.len() + 1 != peaks.len() {
+            return false;
+        }
+
+        let mut current = *leaf;
+        let mut pos = leaf_pos;
+        for (height, sibling) in self.siblings.iter().enumerate() {
+            if pos_height(pos + 1) > height {
+                current = H::concat_and_hash(sibling, &current);
+                pos += 1;
+            } else {
+                current = H::concat_and_hash(&current, sibling);
+                pos += 2 << height;
+            }
+            // Climbed past the peak: the proof has too many siblings
+            if pos > peaks[peak_idx] {
+                return false;
+            }
+        }
+        if pos != peaks[peak_idx] {
+            return false;
+        }
+
+        let mut all_peaks = self.peaks.clone();
+        all_peaks.insert(peak_idx, current);
+        bag_peaks::<H>(&all_peaks) == Some(*root)
+    }
+}
+
+// Folds the peaks from right to left into a single root.
+pub fn bag_peaks<H: Hasher>(peaks: &[H::Hash]) -> Option<H::Hash> {
+    let (last, rest) = peaks.split_last()?;
+    Some(rest.iter().rev().fold(*last, |acc, peak| H::concat_and_hash(peak, &acc)))
+}
+
+// Number of nodes in an MMR with the given number of leaves, or None if it does not fit in a usize.
+pub fn leaves_to_mmr_size(leaves_count: usize) -> Option<usize> {
+    Some(leaves_count.checked_mul(2)? - leaves_count.count_ones() as usize)
+}
+
+// Position of a leaf given its index among leaves, i.e. the size of the MMR before it.
+fn leaf_index_to_pos(leaf_index: usize) -> Option<usize> {
+    leaves_to_mmr_size(leaf_index)
+}
+
+// Height of the node at the given position (leaves have height 0).
+fn pos_height(pos: usize) -> usize {
+    // Work with 1-based positions: the leftmost node at each height is 2^(h+1) - 1, i.e. all ones.
+    let mut pos = pos + 1;
+    while pos & (pos + 1) != 0 {
+        // Jump to the node at the same height in the leftmost mountain
+        let msb = usize::BITS - 1 - pos.leading_zeros();
+        pos -= (1 << msb) - 1;
+    }
+    (usize::BITS - pos.leading_zeros()) as usize - 1
+}
+
+// Splits an MMR size into mountains of size 2^(h+1) - 1 with strictly decreasing heights.
+// Each mountain is larger than all smaller ones combined, so a greedy split is exact.
+fn mountains(mmr_size: usize) -> (Vec<usize>, usize) {
+    let mut remaining = mmr_size;
+    let mut sizes = Vec::new();
+    for height in (0..usize::BITS - 1).rev() {
+        let size = (1usize << (height + 1)) - 1;
+        if remaining >= size {
+            sizes.push(size);
+            remaining -= size;
+        }
+    }
+    (sizes, remaining)
+}
+
+fn is_valid_mmr_size(mmr_size: usize) -> bool {
+    mountains(mmr_size).1 == 0
+}
+
+// Positions of the peaks from left to right.
+fn peak_positions(mmr_size: usize) -> Vec<usize> {
+    let mut offset = 0;
+    mountains(mmr_size)
+        .0
+        .into_iter()
+        .map(|size| {
+            offset += size;
+            offset - 1
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sha256::Sha256Hasher;
+    use ark_bn254::Fr;
+
+    fn build_mock_mmr(leaves_count: u64) -> MerkleMountainRange<PoseidonAlgorithm> {
+        let mut mmr = MerkleMountainRange::new();
+        (0..leaves_count).for_each(|leaf| {
+            mmr.append(Fr::from(leaf));
+        });
+        mmr
+    }
+
+    #[test]
+    fn test_positions_and_heights() {
+        assert_eq!(
+            (0..11).map(pos_height).collect::<Vec<_>>(),
+            vec![0, 0, 1, 0, 0, 1, 2, 0, 0, 1, 0]
+        );
+        assert_eq!((0..5).map(leaf_index_to_pos).collect::<Vec<_>>(), [0, 1, 3, 4, 7].map(Some).to_vec());
+        assert_eq!(leaf_index_to_pos(usize::MAX), None);
+        assert_eq!(peak_positions(11), vec![6, 9, 10]);
+        assert!(is_valid_mmr_size(11));
+        assert!(!is_valid_mmr_size(5));
+    }
+
+    #[test]
+    fn test_append_and_root() {
+        let mmr = build_mock_mmr(3);
+        assert_eq!(mmr.mmr_size(), 4);
+        assert_eq!(mmr.leaves_count(), 3);
+
+        let left = PoseidonAlgorithm::concat_and_hash(&Fr::from(0u64), &Fr::from(1u64));
+        assert_eq!(mmr.peaks(), vec![left, Fr::from(2u64)]);
+        assert_eq!(mmr.root(), Some(PoseidonAlgorithm::concat_and_hash(&left, &Fr::from(2u64))));
+        assert_eq!(mmr.value(2), Some(&Fr::from(2u64)));
+        assert_eq!(mmr.value(3), None);
+
+        assert_eq!(MerkleMountainRange::<PoseidonAlgorithm>::new().root(), None);
+    }
+
+    #[test]
+    fn test_opening_all_leaves() {
+        let mmr = build_mock_mmr(11);
+        let root = mmr.root().unwrap();
+        for leaf_index in 0..11 {
+            let proof = mmr.opening(leaf_index, mmr.mmr_size()).unwrap();
+            assert!(proof.verify(&root, mmr.value(leaf_index).unwrap()));
+            assert!(!proof.verify(&root, &Fr::from(100u64)));
+        }
+        assert!(mmr.opening(11, mmr.mmr_size()).is_none());
+        assert!(mmr.opening(usize::MAX, mmr.mmr_size()).is_none());
+    }
+
+    #[test]
+    fn test_verify_rejects_aliased_index() {
+        let mmr = build_mock_mmr(5);
+        let root = mmr.root().unwrap();
+        let proof = mmr.opening(0, mmr.mmr_size()).unwrap();
+        assert!(proof.verify(&root, &Fr::from(0u64)));
+
+        // 2 * index wraps around to the position of leaf 0
+        for leaf_index in [(1 << (usize::BITS - 1)) + 1, usize::MAX] {
+            let aliased = MmrProof { leaf_index, ..proof.clone() };
+            assert!(!aliased.verify(&root, &Fr::from(0u64)));
+        }
+    }
+
+    #[test]
+    fn test_opening_against_older_size() {
+        let mut mmr = build_mock_mmr(7);
+        let old_size = mmr.mmr_size();
+        let old_root = mmr.root().unwrap();
+        for leaf in 7..20u64 {
+            mmr.append(Fr::from(leaf));
+        }
+
+        assert_eq!(mmr.root_at(old_size), Some(old_root));
+        let proof = mmr.opening(5, old_size).unwrap();
+        assert!(proof.verify(&old_root, &Fr::from(5u64)));
+        assert!(!proof.verify(&mmr.root().unwrap(), &Fr::from(5u64)));
+        assert!(mmr.opening(8, old_size).is_none());
+        assert!(mmr.opening(0, old_size + 1).is_none());
+    }
+
+    #[test]
+    fn test_sha256_mmr() {
+        let mut mmr = MerkleMountainRange::<Sha256Hasher>::new();
+        for leaf in 0..33 {
+            mmr.append(Sha256Hasher::hash_leaf_data(leaf.to_string().as_bytes()));
+        }
+        assert_eq!(Some(mmr.mmr_size()), leaves_to_mmr_size(33));
+        assert_eq!(leaves_to_mmr_size(usize::MAX / 2 + 1), None);
+        let root = mmr.root().unwrap();
+        let proof = mmr.opening(17, mmr.mmr_size()).unwrap();
+        assert!(proof.verify(&root, &Sha256Hasher::hash_leaf_data(b"17")));
+    }
+}
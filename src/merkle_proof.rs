@@ -0,0 +1,644 @@
+use crate::hasher::Hasher;
+use crate::incremental_merkle_tree::Opening;
+use crate::poseidon::PoseidonAlgorithm;
+use ark_serialize::{CanonicalDeserialize, CanonicalSerialize, Compress, Read, SerializationError, Valid, Validate, Write};
+
+// Inclusion proof of a single leaf: everything needed to recompute the root, so it can be checked
+// by a light client that only holds the root (see verify).
+#[derive(Debug, Clone)]
+pub struct MerkleProof<H = PoseidonAlgorithm>
+where
+    H: Hasher,
+{
+    pub leaf_index: usize,
+    pub leaf: H::Hash,
+    // One sibling per level from the leaves up to (not including) the root.
+    pub siblings: Vec<H::Hash>,
+}
+
+impl<H> PartialEq for MerkleProof<H>
+where
+    H: Hasher,
+{
+    fn eq(&self, other: &Self) -> bool {
+        self.leaf_index == other.leaf_index && self.leaf == other.leaf && self.siblings == other.siblings
+    }
+}
+
+impl<H> Eq for MerkleProof<H> where H: Hasher {}
+
+impl<H> MerkleProof<H>
+where
+    H: Hasher,
+{
+    pub fn new(leaf_index: usize, leaf: H::Hash, siblings: Vec<H::Hash>) -> Self {
+        Self {
+            leaf_index,
+            leaf,
+            siblings,
+        }
+    }
+
+    // From the format of MerkleTree::opening_orig, which does not contain the leaf itself.
+    pub fn from_opening_orig(leaf_index: usize, leaf: H::Hash, opening: Vec<H::Hash>) -> Self {
+        Self::new(leaf_index, leaf, opening)
+    }
+
+    // From the format of MerkleTree::opening. Returns None if the opening is empty (the leaf is not in it)
+    // or if the node on the path at some level is not the hash of the pair below it.
+    pub fn from_opening(leaf_index: usize, opening: &[(H::Hash, H::Hash)]) -> Option<Self> {
+        let leaf = match opening.first() {
+            Some((left, right)) => if leaf_index.is_multiple_of(2) { *left } else { *right },
+            None => return None,
+        };
+
+        let mut siblings = Vec::with_capacity(opening.len());
+        let mut current = leaf;
+        let mut index = leaf_index;
+        for (left, right) in opening.iter() {
+            let (on_path, sibling) = if index.is_multiple_of(2) { (left, right) } else { (right, left) };
+            if *on_path != current {
+                return None;
+            }
+            siblings.push(*sibling);
+            current = H::concat_and_hash(left, right);
+            index /= 2;
+        }
+
+        Some(Self::new(leaf_index, leaf, siblings))
+    }
+
+    // Back to the format of MerkleTree::opening.
+    pub fn to_opening(&self) -> Opening<H> {
+        let mut opening = Vec::with_capacity(self.siblings.len());
+        let mut current = self.leaf;
+        let mut index = self.leaf_index;
+        for sibling in self.siblings.iter() {
+            let pair = if index.is_multiple_of(2) { (current, *sibling) } else { (*sibling, current) };
+            current = H::concat_and_hash(&pair.0, &pair.1);
+            opening.push(pair);
+            index /= 2;
+        }
+        opening
+    }
+
+    // Root of the tree in which the leaf sits at leaf_index with these siblings.
+    pub fn compute_root(&self) -> H::Hash {
+        let mut current = self.leaf;
+        let mut index = self.leaf_index;
+        for sibling in self.siblings.iter() {
+            if index.is_multiple_of(2) {
+                current = H::concat_and_hash(&current, sibling);
+            } else {
+                current = H::concat_and_hash(sibling, &current);
+            }
+            index /= 2;
+        }
+        current
+    }
+}
+
+// Stateless verification against a root, e.g. for light clients.
+pub fn verify<H: Hasher>(root: &H::Hash, proof: &MerkleProof<H>) -> bool {
+    // The index must fit in a tree with this many levels.
+    let fits = proof.siblings.len() >= usize::BITS as usize || proof.leaf_index >> proof.siblings.len() == 0;
+    fits && proof.compute_root() == *root
+}
+
+impl<H> CanonicalSerialize for MerkleProof<H>
+where
+    H: Hasher,
+{
+    fn serialize_with_mode<W: Write>(&self, mut writer: W, compress: Compress) -> Result<(), SerializationError> {
+        self.leaf_index.serialize_with_mode(&mut writer, compress)?;
+        self.leaf.serialize_with_mode(&mut writer, compress)?;
+        self.siblings.serialize_with_mode(&mut writer, compress)
+    }
+
+    fn serialized_size(&self, compress: Compress) -> usize {
+        self.leaf_index.serialized_size(compress)
+            + self.leaf.serialized_size(compress)
+            + self.siblings.serialized_size(compress)
+    }
+}
+
+impl<H> Valid for MerkleProof<H>
+where
+    H: Hasher,
+{
+    fn check(&self) -> Result<(), SerializationError> {
+        self.leaf.check()?;
+        self.siblings.check()
+    }
+}
+
+impl<H> CanonicalDeserialize for MerkleProof<H>
+where
+    H: Hasher,
+{
+    fn deserialize_with_mode<R: Read>(
+        mut reader: R,
+        compress: Compress,
+        validate: Validate,
+    ) -> Result<Self, SerializationError> {
+        let leaf_index = usize::deserialize_with_mode(&mut reader, compress, validate)?;
+        let leaf = H::Hash::deserialize_with_mode(&mut reader, compress, validate)?;
+        let siblings = Vec::<H::Hash>::deserialize_with_mode(&mut reader, compress, validate)?;
+        Ok(Self::new(leaf_index, leaf, siblings))
+    }
+}
+
+// Inclusion proof of several leaves at once, in the spirit of rs-merkle's MerkleProof: nodes that can be computed
+// from the leaves (including the siblings that are themselves on a path) are left out, so every node appears once.
+#[derive(Debug, Clone)]
+pub struct MultiProof<H = PoseidonAlgorithm>
+where
+    H: Hasher,
+{
+    // Levels of the tree, including the root (as MerkleTree::depth).
+    pub depth: usize,
+    // Strictly increasing.
+    pub leaf_indices: Vec<usize>,
+    pub leaves: Vec<H::Hash>,
+    // Siblings that are not on any path, level by level from the leaves up, left to right within a level.
+    pub siblings: Vec<H::Hash>,
+}
+
+impl<H> PartialEq for MultiProof<H>
+where
+    H: Hasher,
+{
+    fn eq(&self, other: &Self) -> bool {
+        self.depth == other.depth
+            && self.leaf_indices == other.leaf_indices
+            && self.leaves == other.leaves
+            && self.siblings == other.siblings
+    }
+}
+
+impl<H> Eq for MultiProof<H> where H: Hasher {}
+
+impl<H> MultiProof<H>
+where
+    H: Hasher,
+{
+    pub fn new(depth: usize, leaf_indices: Vec<usize>, leaves: Vec<H::Hash>, siblings: Vec<H::Hash>) -> Self {
+        Self {
+            depth,
+            leaf_indices,
+            leaves,
+            siblings,
+        }
+    }
+
+    // Root of the tree in which the leaves sit at leaf_indices with these siblings.
+    // None if the proof is malformed: no leaves, unsorted or out of range indices, or too few or too many siblings.
+    pub fn compute_root(&self) -> Option<H::Hash> {
+        if self.depth == 0 || self.leaf_indices.is_empty() || self.leaf_indices.len() != self.leaves.len() {
+            return None;
+        }
+        let levels = self.depth - 1;
+        let fits = |index: usize| levels >= usize::BITS as usize || index >> levels == 0;
+        if !self.leaf_indices.windows(2).all(|pair| pair[0] < pair[1]) || !self.leaf_indices.iter().all(|&index| fits(index)) {
+            return None;
+        }
+
+        let mut nodes: Vec<(usize, H::Hash)> = self.leaf_indices.iter().copied().zip(self.leaves.iter().copied()).collect();
+        let mut siblings = self.siblings.iter();
+        for _ in 0..levels {
+            let mut parents = Vec::with_capacity(nodes.len());
+            let mut i = 0;
+            while i < nodes.len() {
+                let (index, node) = nodes[i];
+                let parent = if index.is_multiple_of(2) {
+                    match nodes.get(i + 1) {
+                        // Both children are known
+                        Some((next_index, next_node)) if *next_index == index + 1 => {
+                            i += 1;
+                            H::concat_and_hash(&node, next_node)
+                        }
+                        _ => H::concat_and_hash(&node, siblings.next()?),
+                    }
+                } else {
+                    H::concat_and_hash(siblings.next()?, &node)
+                };
+                parents.push((index / 2, parent));
+                i += 1;
+            }
+            nodes = parents;
+        }
+
+        if siblings.next().is_some() {
+            return None;
+        }
+        Some(nodes[0].1)
+    }
+}
+
+// Stateless verification of a MultiProof against a root.
+pub fn verify_multi<H: Hasher>(root: &H::Hash, proof: &MultiProof<H>) -> bool {
+    proof.compute_root() == Some(*root)
+}
+
+impl<H> CanonicalSerialize for MultiProof<H>
+where
+    H: Hasher,
+{
+    fn serialize_with_mode<W: Write>(&self, mut writer: W, compress: Compress) -> Result<(), SerializationError> {
+        self.depth.serialize_with_mode(&mut writer, compress)?;
+        self.leaf_indices.serialize_with_mode(&mut writer, compress)?;
+        self.leaves.serialize_with_mode(&mut writer, compress)?;
+        self.siblings.serialize_with_mode(&mut writer, compress)
+    }
+
+    fn serialized_size(&self, compress: Compress) -> usize {
+        self.depth.serialized_size(compress)
+            + self.leaf_indices.serialized_size(compress)
+            + self.leaves.serialized_size(compress)
+            + self.siblings.serialized_size(compress)
+    }
+}
+
+impl<H> Valid for MultiProof<H>
+where
+    H: Hasher,
+{
+    fn check(&self) -> Result<(), SerializationError> {
+        self.leaves.check()?;
+        self.siblings.check()
+    }
+}
+
+impl<H> CanonicalDeserialize for MultiProof<H>
+where
+    H: Hasher,
+{
+    fn deserialize_with_mode<R: Read>(
+        mut reader: R,
+        compress: Compress,
+        validate: Validate,
+    ) -> Result<Self, SerializationError> {
+        let depth = usize::deserialize_with_mode(&mut reader, compress, validate)?;
+        let leaf_indices = Vec::<usize>::deserialize_with_mode(&mut reader, compress, validate)?;
+        let leaves = Vec::<H::Hash>::deserialize_with_mode(&mut reader, compress, validate)?;
+        let siblings = Vec::<H::Hash>::deserialize_with_mode(&mut reader, compress, validate)?;
+        Ok(Self::new(depth, leaf_indices, leaves, siblings))
+    }
+}
+
+// Proof that the tree with new_size leaves extends the tree with old_size leaves, i.e. that the first old_size
+// leaves are unchanged (RFC 9162 style, for the fixed-depth trees of MerkleTree, where missing leaves are empty).
+// Both roots are recomputed from the path of the last old leaf: the siblings on its left are shared by both trees,
+// and the siblings on its right are empty in the old tree and given by the proof for the new one.
+#[derive(Debug, Clone)]
+pub struct ConsistencyProof<H = PoseidonAlgorithm>
+where
+    H: Hasher,
+{
+    pub old_size: usize,
+    pub new_size: usize,
+    // The leaf at old_size - 1.
+    pub leaf: H::Hash,
+    // One sibling per level from the leaves up to (not including) the root, in the new tree.
+    pub siblings: Vec<H::Hash>,
+}
+
+impl<H> PartialEq for ConsistencyProof<H>
+where
+    H: Hasher,
+{
+    fn eq(&self, other: &Self) -> bool {
+        self.old_size == other.old_size
+            && self.new_size == other.new_size
+            && self.leaf == other.leaf
+            && self.siblings == other.siblings
+    }
+}
+
+impl<H> Eq for ConsistencyProof<H> where H: Hasher {}
+
+impl<H> ConsistencyProof<H>
+where
+    H: Hasher,
+{
+    pub fn new(old_size: usize, new_size: usize, leaf: H::Hash, siblings: Vec<H::Hash>) -> Self {
+        Self {
+            old_size,
+            new_size,
+            leaf,
+            siblings,
+        }
+    }
+
+    // The (old, new) roots this proof links, or None if the sizes are invalid or a right sibling that only
+    // covers leaves from new_size on is not empty.
+    pub fn compute_roots(&self) -> Option<(H::Hash, H::Hash)> {
+        let levels = self.siblings.len();
+        let fits = |size: usize| levels >= usize::BITS as usize || size <= 1usize << levels;
+        if self.old_size == 0 || self.old_size > self.new_size || !fits(self.new_size) {
+            return None;
+        }
+
+        let mut empty_hash = H::Hash::default();
+        let mut old_root = self.leaf;
+        let mut new_root = self.leaf;
+        let mut index = self.old_size - 1;
+        for (level, sibling) in self.siblings.iter().enumerate() {
+            if index.is_multiple_of(2) {
+                // The sibling covers leaves from (index + 1) << level on, which is beyond new_size if it overflows
+                let first_leaf = 1usize.checked_shl(level as u32).and_then(|width| (index + 1).checked_mul(width));
+                if first_leaf.is_none_or(|first_leaf| first_leaf >= self.new_size) && *sibling != empty_hash {
+                    return None;
+                }
+                old_root = H::concat_and_hash(&old_root, &empty_hash);
+                new_root = H::concat_and_hash(&new_root, sibling);
+            } else {
+                old_root = H::concat_and_hash(sibling, &old_root);
+                new_root = H::concat_and_hash(sibling, &new_root);
+            }
+            empty_hash = H::concat_and_hash(&empty_hash, &empty_hash);
+            index /= 2;
+        }
+        Some((old_root, new_root))
+    }
+}
+
+// Checks that the tree with root new_root extends the tree with root old_root.
+pub fn verify_consistency<H: Hasher>(old_root: &H::Hash, new_root: &H::Hash, proof: &ConsistencyProof<H>) -> bool {
+    proof.compute_roots() == Some((*old_root, *new_root))
+}
+
+impl<H> CanonicalSerialize for ConsistencyProof<H>
+where
+    H: Hasher,
+{
+    fn serialize_with_mode<W: Write>(&self, mut writer: W, compress: Compress) -> Result<(), SerializationError> {
+        self.old_size.serialize_with_mode(&mut writer, compress)?;
+        self.new_size.serialize_with_mode(&mut writer, compress)?;
+        self.leaf.serialize_with_mode(&mut writer, compress)?;
+        self.siblings.serialize_with_mode(&mut writer, compress)
+    }
+
+    fn serialized_size(&self, compress: Compress) -> usize {
+        self.old_size.serialized_size(compress)
+            + self.new_size.serialized_size(compress)
+            + self.leaf.serialized_size(compress)
+            + self.siblings.serialized_size(compress)
+    }
+}
+
+impl<H> Valid for ConsistencyProof<H>
+where
+    H: Hasher,
+{
+    fn check(&self) -> Result<(), SerializationError> {
+        self.leaf.check()?;
+        self.siblings.check()
+    }
+}
+
+impl<H> CanonicalDeserialize for ConsistencyProof<H>
+where
+    H: Hasher,
+{
+    fn deserialize_with_mode<R: Read>(
+        mut reader: R,
+        compress: Compress,
+        validate: Validate,
+    ) -> Result<Self, SerializationError> {
+        let old_size = usize::deserialize_with_mode(&mut reader, compress, validate)?;
+        let new_size = usize::deserialize_with_mode(&mut reader, compress, validate)?;
+        let leaf = H::Hash::deserialize_with_mode(&mut reader, compress, validate)?;
+        let siblings = Vec::<H::Hash>::deserialize_with_mode(&mut reader, compress, validate)?;
+        Ok(Self::new(old_size, new_size, leaf, siblings))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::MerkleTreeError;
+    use crate::incremental_merkle_tree::MerkleTree;
+    use crate::test_utils::build_mock_tree;
+    use crate::sha256::Sha256Hasher;
+    use ark_bn254::Fr;
+
+    #[test]
+    fn test_proof_from_both_opening_formats() {
+        let mut merkle_tree = MerkleTree::<PoseidonAlgorithm>::build_empty_tree(5).unwrap();
+        build_mock_tree(&mut merkle_tree, 11, |leaf| Fr::from(leaf as u64 + 1));
+        let root = *merkle_tree.root().unwrap();
+        for leaf_index in [0usize, 3, 10, 15] {
+            let leaf = *merkle_tree.value(leaf_index).unwrap();
+            let from_orig = MerkleProof::<PoseidonAlgorithm>::from_opening_orig(
+                leaf_index,
+                leaf,
+                merkle_tree.opening_orig(leaf_index).unwrap(),
+            );
+            let opening = merkle_tree.opening(leaf_index as u32).unwrap();
+            let from_pairs = MerkleProof::<PoseidonAlgorithm>::from_opening(leaf_index, &opening).unwrap();
+
+            assert_eq!(from_orig, from_pairs);
+            assert_eq!(merkle_tree.proof(leaf_index), Ok(from_orig.clone()));
+            assert_eq!(from_pairs.to_opening(), opening);
+            assert!(verify(&root, &from_orig));
+        }
+        assert_eq!(merkle_tree.proof(16), Err(MerkleTreeError::IndexOutOfRange { index: 16, capacity: 16 }));
+    }
+
+    #[test]
+    fn test_verify_rejects_bad_proofs() {
+        let mut merkle_tree = MerkleTree::<PoseidonAlgorithm>::build_empty_tree(5).unwrap();
+        build_mock_tree(&mut merkle_tree, 11, |leaf| Fr::from(leaf as u64 + 1));
+        let root = *merkle_tree.root().unwrap();
+        let proof = merkle_tree.proof(6).unwrap();
+
+        let mut wrong_leaf = proof.clone();
+        wrong_leaf.leaf = Fr::from(100u64);
+        assert!(!verify(&root, &wrong_leaf));
+
+        let mut wrong_index = proof.clone();
+        wrong_index.leaf_index = 7;
+        assert!(!verify(&root, &wrong_index));
+
+        // Same path bits, but the index does not fit in the tree
+        let mut out_of_range = proof.clone();
+        out_of_range.leaf_index = 6 + 16;
+        assert!(!verify(&root, &out_of_range));
+
+        let mut short = proof;
+        short.siblings.pop();
+        assert!(!verify(&root, &short));
+    }
+
+    #[test]
+    fn test_from_inconsistent_opening() {
+        let mut merkle_tree = MerkleTree::<PoseidonAlgorithm>::build_empty_tree(5).unwrap();
+        build_mock_tree(&mut merkle_tree, 11, |leaf| Fr::from(leaf as u64 + 1));
+        let mut opening = merkle_tree.opening(4).unwrap();
+        opening[2].0 = Fr::from(0u64);
+        assert!(MerkleProof::<PoseidonAlgorithm>::from_opening(4, &opening).is_none());
+        assert!(MerkleProof::<PoseidonAlgorithm>::from_opening(4, &[]).is_none());
+    }
+
+    #[test]
+    fn test_multi_proof() {
+        let mut merkle_tree = MerkleTree::<PoseidonAlgorithm>::build_empty_tree(5).unwrap();
+        build_mock_tree(&mut merkle_tree, 11, |leaf| Fr::from(leaf as u64 + 1));
+        let root = *merkle_tree.root().unwrap();
+
+        // Duplicates and order do not matter
+        let proof = merkle_tree.multi_opening(&[6, 0, 7, 3, 6]).unwrap();
+        assert_eq!(proof.leaf_indices, vec![0, 3, 6, 7]);
+        assert_eq!(proof.leaves, vec![Fr::from(1u64), Fr::from(4u64), Fr::from(7u64), Fr::from(8u64)]);
+        // Leaves 1 and 2, the node above 4-5, and the right half of the tree
+        assert_eq!(proof.siblings.len(), 4);
+        assert!(verify_multi(&root, &proof));
+
+        // Fewer siblings than separate openings
+        let separate: usize = [0usize, 3, 6, 7].iter().map(|&i| merkle_tree.proof(i).unwrap().siblings.len()).sum();
+        assert!(proof.siblings.len() < separate);
+
+        for leaf_indices in [vec![0u32], vec![15], vec![0, 15], (0..16).collect::<Vec<_>>(), vec![1, 2, 5, 10, 11, 14]] {
+            let proof = merkle_tree.multi_opening(&leaf_indices).unwrap();
+            assert!(verify_multi(&root, &proof));
+        }
+        assert_eq!(merkle_tree.multi_opening(&(0..16).collect::<Vec<_>>()).unwrap().siblings.len(), 0);
+        assert_eq!(
+            merkle_tree.multi_opening(&[3, 16]),
+            Err(MerkleTreeError::IndexOutOfRange { index: 16, capacity: 16 })
+        );
+    }
+
+    #[test]
+    fn test_verify_multi_rejects_bad_proofs() {
+        let mut merkle_tree = MerkleTree::<PoseidonAlgorithm>::build_empty_tree(5).unwrap();
+        build_mock_tree(&mut merkle_tree, 11, |leaf| Fr::from(leaf as u64 + 1));
+        let root = *merkle_tree.root().unwrap();
+        let proof = merkle_tree.multi_opening(&[2, 9, 10]).unwrap();
+        assert!(verify_multi(&root, &proof));
+
+        let mut wrong_leaf = proof.clone();
+        wrong_leaf.leaves[1] = Fr::from(0u64);
+        assert!(!verify_multi(&root, &wrong_leaf));
+
+        let mut wrong_index = proof.clone();
+        wrong_index.leaf_indices[1] = 8;
+        assert!(!verify_multi(&root, &wrong_index));
+
+        let mut unsorted = proof.clone();
+        unsorted.leaf_indices.swap(0, 1);
+        unsorted.leaves.swap(0, 1);
+        assert!(!verify_multi(&root, &unsorted));
+
+        let mut out_of_range = proof.clone();
+        out_of_range.leaf_indices[2] = 10 + 16;
+        assert!(!verify_multi(&root, &out_of_range));
+
+        let mut missing_sibling = proof.clone();
+        missing_sibling.siblings.pop();
+        assert!(!verify_multi(&root, &missing_sibling));
+
+        let mut extra_sibling = proof.clone();
+        extra_sibling.siblings.push(Fr::from(0u64));
+        assert!(!verify_multi(&root, &extra_sibling));
+
+        let empty = merkle_tree.multi_opening(&[]).unwrap();
+        assert!(!verify_multi(&root, &empty));
+
+        let mut bytes = Vec::new();
+        proof.serialize_compressed(&mut bytes).unwrap();
+        assert_eq!(MultiProof::<PoseidonAlgorithm>::deserialize_compressed(bytes.as_slice()).unwrap(), proof);
+    }
+
+    #[test]
+    fn test_consistency_proof() {
+        let mut merkle_tree = MerkleTree::<PoseidonAlgorithm>::build_empty_tree(5).unwrap();
+        let mut roots = vec![*merkle_tree.root().unwrap()];
+        for leaf in 0..13u64 {
+            merkle_tree.insert_leaf(Fr::from(leaf * 2 + 1)).unwrap();
+            roots.push(*merkle_tree.root().unwrap());
+        }
+
+        for old_size in 1..=13 {
+            for new_size in old_size..=13 {
+                let proof = merkle_tree.consistency_proof(old_size, new_size).unwrap();
+                assert!(verify_consistency(&roots[old_size], &roots[new_size], &proof));
+                if old_size < new_size {
+                    assert!(!verify_consistency(&roots[new_size], &roots[old_size], &proof));
+                }
+            }
+        }
+
+        let invalid = |size, max| Err(MerkleTreeError::InvalidTreeSize { size, max });
+        assert_eq!(merkle_tree.consistency_proof(0, 5), invalid(0, 5));
+        assert_eq!(merkle_tree.consistency_proof(6, 5), invalid(6, 5));
+        assert_eq!(merkle_tree.consistency_proof(3, 14), invalid(14, 13));
+    }
+
+    #[test]
+    fn test_consistency_detects_rewritten_history() {
+        let mut merkle_tree = MerkleTree::<PoseidonAlgorithm>::build_empty_tree(5).unwrap();
+        merkle_tree.insert_leaves(&(1..=6u64).map(Fr::from).collect::<Vec<_>>()).unwrap();
+        let old_root = *merkle_tree.root().unwrap();
+        merkle_tree.insert_leaves(&[Fr::from(7u64), Fr::from(8u64), Fr::from(9u64)]).unwrap();
+
+        let proof = merkle_tree.consistency_proof(6, 9).unwrap();
+        let new_root = *merkle_tree.root().unwrap();
+        assert!(verify_consistency(&old_root, &new_root, &proof));
+
+        let mut wrong_size = proof.clone();
+        wrong_size.old_size = 5;
+        assert!(!verify_consistency(&old_root, &new_root, &wrong_size));
+
+        // A new size too small for the leaves covered by the proof
+        let mut wrong_new_size = proof.clone();
+        wrong_new_size.new_size = 6;
+        assert!(!verify_consistency(&old_root, &new_root, &wrong_new_size));
+
+        // Once a leaf of the old tree is rewritten, no proof links the published roots
+        merkle_tree.update_leaf(2, Fr::from(100u64)).unwrap();
+        let rewritten_root = *merkle_tree.root().unwrap();
+        let proof = merkle_tree.consistency_proof(6, 9).unwrap();
+        assert!(!verify_consistency(&old_root, &rewritten_root, &proof));
+
+        let mut bytes = Vec::new();
+        proof.serialize_compressed(&mut bytes).unwrap();
+        assert_eq!(ConsistencyProof::<PoseidonAlgorithm>::deserialize_compressed(bytes.as_slice()).unwrap(), proof);
+    }
+
+    #[test]
+    fn test_oversized_consistency_proof() {
+        // More levels than bits in a usize: the right siblings past the 64th level cover no leaves and must be empty
+        let mut empty_hashes = vec![Fr::from(0u64)];
+        for _ in 1..70 {
+            let last = empty_hashes[empty_hashes.len() - 1];
+            empty_hashes.push(PoseidonAlgorithm::concat_and_hash(&last, &last));
+        }
+        let proof = ConsistencyProof::<PoseidonAlgorithm>::new(1, 1, Fr::from(3u64), empty_hashes);
+        let (old_root, new_root) = proof.compute_roots().unwrap();
+        assert_eq!(old_root, new_root);
+        assert!(verify_consistency(&old_root, &new_root, &proof));
+
+        for level in [63, 64, 69] {
+            let mut forged = proof.clone();
+            forged.siblings[level] = Fr::from(1u64);
+            assert_eq!(forged.compute_roots(), None);
+        }
+    }
+
+    #[test]
+    fn test_serialize_proof() {
+        let mut merkle_tree = MerkleTree::<Sha256Hasher>::build_empty_tree(4).unwrap();
+        for leaf in 0..5u32 {
+            merkle_tree.insert_leaf(Sha256Hasher::hash_leaf_data(&leaf.to_be_bytes())).unwrap();
+        }
+        let proof = merkle_tree.proof(4).unwrap();
+
+        let mut bytes = Vec::new();
+        proof.serialize_compressed(&mut bytes).unwrap();
+        let restored = MerkleProof::<Sha256Hasher>::deserialize_compressed(bytes.as_slice()).unwrap();
+        assert_eq!(restored, proof);
+        assert!(verify(merkle_tree.root().unwrap(), &restored));
+    }
+}
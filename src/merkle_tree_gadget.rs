@@ -0,0 +1,149 @@
+use crate::poseidon_gadget::Poseidon2Gadget;
+use ark_bn254::Fr;
+use ark_r1cs_std::fields::fp::FpVar;
+use ark_r1cs_std::prelude::*;
+use ark_relations::r1cs::{ConstraintSynthesizer, ConstraintSystemRef, Namespace, SynthesisError};
+use core::borrow::Borrow;
+
+/* R1CS membership check for openings of a Poseidon2 MerkleTree.
+   The path has the format of MerkleTree::opening: one (left, right) pair per level from the leaves up,
+   where one of the two is the node on the path and the other is its sibling. */
+
+#[derive(Clone)]
+pub struct MerklePathVar {
+    pub path: Vec<(FpVar<Fr>, FpVar<Fr>)>,
+}
+
+impl AllocVar<Vec<(Fr, Fr)>, Fr> for MerklePathVar {
+    fn new_variable<T: Borrow<Vec<(Fr, Fr)>>>(
+        cs: impl Into<Namespace<Fr>>,
+        f: impl FnOnce() -> Result<T, SynthesisError>,
+        mode: AllocationMode,
+    ) -> Result<Self, SynthesisError> {
+        let ns = cs.into();
+        let cs = ns.cs();
+        let opening = f()?;
+        let path = opening
+            .borrow()
+            .iter()
+            .map(|(left, right)| {
+                Ok((
+                    FpVar::new_variable(cs.clone(), || Ok(*left), mode)?,
+                    FpVar::new_variable(cs.clone(), || Ok(*right), mode)?,
+                ))
+            })
+            .collect::<Result<Vec<_>, SynthesisError>>()?;
+        Ok(MerklePathVar { path })
+    }
+}
+
+impl MerklePathVar {
+    // Enforces that `leaf` is at `leaf_index` in the tree with root `root`:
+    // the bits of `leaf_index` (least significant first) choose on which side of each pair the current node is,
+    // and each pair hashes to the node of the next level.
+    pub fn enforce_membership(
+        &self,
+        leaf: &FpVar<Fr>,
+        leaf_index: &FpVar<Fr>,
+        root: &FpVar<Fr>,
+    ) -> Result<(), SynthesisError> {
+        let poseidon = Poseidon2Gadget::new();
+        let index_bits = leaf_index.to_bits_le()?;
+        if self.path.len() > index_bits.len() {
+            return Err(SynthesisError::Unsatisfiable);
+        }
+
+        // The index must fit in the tree
+        for bit in index_bits[self.path.len()..].iter() {
+            bit.enforce_equal(&Boolean::FALSE)?;
+        }
+
+        let mut current = leaf.clone();
+        for ((left, right), is_right) in self.path.iter().zip(index_bits.iter()) {
+            let on_path = is_right.select(right, left)?;
+            on_path.enforce_equal(&current)?;
+            current = poseidon.hash(&[left.clone(), right.clone()])?;
+        }
+
+        current.enforce_equal(root)
+    }
+}
+
+// Circuit proving knowledge of a leaf and its opening in the tree with public root.
+#[derive(Clone)]
+pub struct MerkleMembershipCircuit {
+    pub root: Fr,
+    pub leaf: Fr,
+    pub leaf_index: u32,
+    pub opening: Vec<(Fr, Fr)>,
+}
+
+impl ConstraintSynthesizer<Fr> for MerkleMembershipCircuit {
+    fn generate_constraints(self, cs: ConstraintSystemRef<Fr>) -> Result<(), SynthesisError> {
+        let root = FpVar::new_input(cs.clone(), || Ok(self.root))?;
+        let leaf = FpVar::new_witness(cs.clone(), || Ok(self.leaf))?;
+        let leaf_index = FpVar::new_witness(cs.clone(), || Ok(Fr::from(self.leaf_index)))?;
+        let path = MerklePathVar::new_witness(cs, || Ok(self.opening))?;
+        path.enforce_membership(&leaf, &leaf_index, &root)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::incremental_merkle_tree::MerkleTree;
+    use crate::test_utils::build_mock_tree;
+    use crate::poseidon::PoseidonAlgorithm;
+    use ark_relations::r1cs::ConstraintSystem;
+
+    fn is_satisfied(circuit: MerkleMembershipCircuit) -> bool {
+        let cs = ConstraintSystem::<Fr>::new_ref();
+        circuit.generate_constraints(cs.clone()).unwrap();
+        cs.is_satisfied().unwrap()
+    }
+
+    #[test]
+    fn test_membership_circuit() {
+        let mut merkle_tree = MerkleTree::<PoseidonAlgorithm>::build_empty_tree(5).unwrap();
+        build_mock_tree(&mut merkle_tree, 11, |leaf| Fr::from(leaf as u64 * 3 + 5));
+        for leaf_index in [0u32, 5, 6, 10, 15] {
+            let circuit = MerkleMembershipCircuit {
+                root: *merkle_tree.root().unwrap(),
+                leaf: *merkle_tree.value(leaf_index as usize).unwrap(),
+                leaf_index,
+                opening: merkle_tree.opening(leaf_index).unwrap(),
+            };
+            assert!(is_satisfied(circuit));
+        }
+    }
+
+    #[test]
+    fn test_membership_circuit_rejects_bad_witnesses() {
+        let mut merkle_tree = MerkleTree::<PoseidonAlgorithm>::build_empty_tree(5).unwrap();
+        build_mock_tree(&mut merkle_tree, 11, |leaf| Fr::from(leaf as u64 * 3 + 5));
+        let circuit = MerkleMembershipCircuit {
+            root: *merkle_tree.root().unwrap(),
+            leaf: *merkle_tree.value(6).unwrap(),
+            leaf_index: 6,
+            opening: merkle_tree.opening(6).unwrap(),
+        };
+
+        let mut wrong_root = circuit.clone();
+        wrong_root.root = *merkle_tree.value(0).unwrap();
+        assert!(!is_satisfied(wrong_root));
+
+        let mut wrong_leaf = circuit.clone();
+        wrong_leaf.leaf = Fr::from(1000u64);
+        assert!(!is_satisfied(wrong_leaf));
+
+        // Right path and leaf, but the index points at the sibling
+        let mut wrong_index = circuit.clone();
+        wrong_index.leaf_index = 7;
+        assert!(!is_satisfied(wrong_index));
+
+        // Index beyond the tree capacity
+        let mut out_of_range = circuit;
+        out_of_range.leaf_index = 6 + 16;
+        assert!(!is_satisfied(out_of_range));
+    }
+}
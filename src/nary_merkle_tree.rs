@@ -0,0 +1,227 @@
+use crate::error::MerkleTreeError;
+use crate::hasher::Hasher;
+use crate::incremental_merkle_tree::{check_depth, empty_level};
+use crate::poseidon::PoseidonAlgorithm;
+
+// Opening as returned by NaryMerkleTree::opening: for each level from the leaves up, the position of the node on
+// the path among its N siblings, and the N - 1 other children of its parent, from left to right.
+pub type NaryOpening<H> = Vec<(usize, Vec<<H as Hasher>::Hash>)>;
+
+// Incremental Merkle tree in which every internal node is H::hash of its N children (N = 2, 4 or 8).
+// With N = 4 the tree is half as deep as the binary MerkleTree for the same capacity, so openings (and membership
+// circuits) have half as many levels. Note that with Poseidon2 (rate 3) hashing 4 children takes two permutations.
+// `depth` counts the levels including the root, so the capacity is N^(depth-1). Empty leaves are H::Hash::default().
+// For N = 2 the roots equal those of MerkleTree for hashers whose concat_and_hash is hash([left, right]).
+#[derive(Debug, Clone)]
+pub struct NaryMerkleTree<const N: usize, H = PoseidonAlgorithm>
+where
+    H: Hasher,
+{
+    pub tree: Vec<Vec<H::Hash>>,
+    pub leaves_count: usize,
+}
+
+impl<const N: usize, H> NaryMerkleTree<N, H>
+where
+    H: Hasher,
+{
+    const SUPPORTED_ARITY: () = assert!(N == 2 || N == 4 || N == 8, "the arity must be 2, 4 or 8");
+
+    pub fn build_empty_tree(depth: u32) -> Result<Self, MerkleTreeError> {
+        let () = Self::SUPPORTED_ARITY;
+        check_depth(depth)?;
+        let capacity = N.checked_pow(depth - 1).ok_or(MerkleTreeError::InvalidDepth(depth))?;
+
+        let mut empty_hashes = Vec::with_capacity(depth as usize);
+        let mut empty_node = H::Hash::default();
+        for _ in 0..depth {
+            empty_hashes.push(empty_node);
+            empty_node = H::hash([empty_node; N]);
+        }
+
+        // From the leaves to the root. All nodes of a level are equal to the empty node of that level.
+        let mut tree = Vec::with_capacity(depth as usize);
+        let mut num_nodes = capacity;
+        for empty_node in empty_hashes.iter() {
+            tree.push(empty_level(*empty_node, num_nodes, depth)?);
+            num_nodes /= N;
+        }
+
+        Ok(Self { tree, leaves_count: 0 })
+    }
+
+    // Appends the leaf, rehashes the N children of every node above it and returns its index.
+    pub fn insert_leaf(&mut self, leaf: H::Hash) -> Result<usize, MerkleTreeError> {
+        if self.leaves_count >= self.capacity() {
+            return Err(MerkleTreeError::TreeFull { capacity: self.capacity() });
+        }
+        let leaf_index = self.leaves_count;
+        self.tree[0][leaf_index] = leaf;
+
+        let mut idx = leaf_index;
+        for level in 0..self.tree.len() - 1 {
+            let first = idx - idx % N;
+            let children: [H::Hash; N] = core::array::from_fn(|i| self.tree[level][first + i]);
+            idx /= N;
+            self.tree[level + 1][idx] = H::hash(children);
+        }
+
+        self.leaves_count += 1;
+        Ok(leaf_index)
+    }
+
+    pub fn leaves_count(&self) -> usize {
+        self.leaves_count
+    }
+
+    pub fn depth(&self) -> usize {
+        self.tree.len()
+    }
+
+    // N^(depth-1) leaf slots.
+    pub fn capacity(&self) -> usize {
+        self.tree[0].len()
+    }
+
+    pub fn root(&self) -> &H::Hash {
+        &self.tree[self.tree.len() - 1][0]
+    }
+
+    pub fn value(&self, leaf_index: usize) -> Result<&H::Hash, MerkleTreeError> {
+        self.check_index(leaf_index)?;
+        Ok(&self.tree[0][leaf_index])
+    }
+
+    pub fn opening(&self, leaf_index: usize) -> Result<NaryOpening<H>, MerkleTreeError> {
+        self.check_index(leaf_index)?;
+        let mut opening = Vec::with_capacity(self.tree.len() - 1);
+        let mut idx = leaf_index;
+        for level in self.tree[..self.tree.len() - 1].iter() {
+            let position = idx % N;
+            let first = idx - position;
+            let siblings = (first..first + N).filter(|&i| i != idx).map(|i| level[i]).collect();
+            opening.push((position, siblings));
+            idx /= N;
+        }
+        Ok(opening)
+    }
+
+    // Stateless check that leaf is at leaf_index in the tree with this root.
+    pub fn verify(root: &H::Hash, leaf_index: usize, leaf: &H::Hash, opening: &NaryOpening<H>) -> bool {
+        let mut idx = leaf_index;
+        let mut current = *leaf;
+        for (position, siblings) in opening.iter() {
+            if *position != idx % N || siblings.len() != N - 1 {
+                return false;
+            }
+            let mut siblings = siblings.iter();
+            let children: [H::Hash; N] = core::array::from_fn(|i| {
+                if i == *position {
+                    current
+                } else {
+                    *siblings.next().expect("N - 1 siblings")
+                }
+            });
+            current = H::hash(children);
+            idx /= N;
+        }
+        // The index must fit in the tree
+        idx == 0 && current == *root
+    }
+
+    fn check_index(&self, leaf_index: usize) -> Result<(), MerkleTreeError> {
+        if leaf_index >= self.capacity() {
+            return Err(MerkleTreeError::IndexOutOfRange { index: leaf_index, capacity: self.capacity() });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::incremental_merkle_tree::MerkleTree;
+    use ark_bn254::Fr;
+
+    fn build_tree<const N: usize>(depth: u32, leaves_count: u64) -> NaryMerkleTree<N> {
+        let mut tree = NaryMerkleTree::<N>::build_empty_tree(depth).unwrap();
+        for leaf in 0..leaves_count {
+            tree.insert_leaf(Fr::from(leaf + 1)).unwrap();
+        }
+        tree
+    }
+
+    #[test]
+    fn test_binary_tree_matches_merkle_tree() {
+        let tree = build_tree::<2>(5, 11);
+        let mut merkle_tree = MerkleTree::<PoseidonAlgorithm>::build_empty_tree(5).unwrap();
+        for leaf in 0..11u64 {
+            merkle_tree.insert_leaf(Fr::from(leaf + 1)).unwrap();
+        }
+        assert_eq!(merkle_tree.root(), Ok(tree.root()));
+        assert_eq!(tree.tree, merkle_tree.tree);
+    }
+
+    #[test]
+    fn test_quaternary_tree() {
+        // 64 leaves in 4 levels instead of 7
+        let tree = build_tree::<4>(4, 45);
+        assert_eq!(tree.capacity(), 64);
+
+        // Recompute the root level by level
+        let mut level: Vec<Fr> = (0..64u64).map(|leaf| if leaf < 45 { Fr::from(leaf + 1) } else { Fr::from(0u64) }).collect();
+        while level.len() > 1 {
+            level = level
+                .chunks(4)
+                .map(|children| PoseidonAlgorithm::hash([children[0], children[1], children[2], children[3]]))
+                .collect();
+        }
+        assert_eq!(*tree.root(), level[0]);
+
+        for leaf_index in [0usize, 7, 44, 63] {
+            let opening = tree.opening(leaf_index).unwrap();
+            assert_eq!(opening.len(), 3);
+            assert!(opening.iter().all(|(_, siblings)| siblings.len() == 3));
+            assert!(NaryMerkleTree::<4>::verify(tree.root(), leaf_index, tree.value(leaf_index).unwrap(), &opening));
+        }
+    }
+
+    #[test]
+    fn test_verify_rejects_bad_openings() {
+        let tree = build_tree::<8>(3, 20);
+        let root = *tree.root();
+        let leaf = *tree.value(13).unwrap();
+        let opening = tree.opening(13).unwrap();
+        assert_eq!(opening[0].0, 5);
+        assert!(NaryMerkleTree::<8>::verify(&root, 13, &leaf, &opening));
+
+        assert!(!NaryMerkleTree::<8>::verify(&root, 13, &Fr::from(0u64), &opening));
+        assert!(!NaryMerkleTree::<8>::verify(&root, 14, &leaf, &opening));
+        assert!(!NaryMerkleTree::<8>::verify(&root, 13 + 64, &leaf, &opening));
+
+        let mut wrong_position = opening.clone();
+        wrong_position[0].0 = 6;
+        assert!(!NaryMerkleTree::<8>::verify(&root, 14, &leaf, &wrong_position));
+
+        let mut missing_sibling = opening.clone();
+        missing_sibling[1].1.pop();
+        assert!(!NaryMerkleTree::<8>::verify(&root, 13, &leaf, &missing_sibling));
+
+        let mut short = opening;
+        short.pop();
+        assert!(!NaryMerkleTree::<8>::verify(&root, 13, &leaf, &short));
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut tree = build_tree::<4>(2, 4);
+        assert_eq!(tree.insert_leaf(Fr::from(5u64)), Err(MerkleTreeError::TreeFull { capacity: 4 }));
+        assert_eq!(tree.opening(4), Err(MerkleTreeError::IndexOutOfRange { index: 4, capacity: 4 }));
+        assert!(NaryMerkleTree::<4>::build_empty_tree(0).is_err());
+        assert_eq!(NaryMerkleTree::<8>::build_empty_tree(23).unwrap_err(), MerkleTreeError::InvalidDepth(23));
+        assert_eq!(NaryMerkleTree::<8>::build_empty_tree(22).unwrap_err(), MerkleTreeError::InvalidDepth(22));
+        // 8^16 leaves do not fit in memory: an error, not an abort
+        assert_eq!(NaryMerkleTree::<8>::build_empty_tree(17).unwrap_err(), MerkleTreeError::InvalidDepth(17));
+        assert_eq!(NaryMerkleTree::<4>::build_empty_tree(26).unwrap_err(), MerkleTreeError::InvalidDepth(26));
+    }
+}
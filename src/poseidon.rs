@@ -3,20 +3,19 @@ use crate::hasher::Hasher;
 
 /* FROM zkmove/smt-circuit */
 
-use ark_ff::{Zero, One, PrimeField, BigInteger};
+use ark_ff::{Zero, One, PrimeField};
 // use crate::default::Default;
 // use crate::hash::Hasher;
 use ark_bn254::Fr;
-use ark_ff::BigInt;
+// use ark_ff::BigInt;
 use lazy_static::lazy_static;
 use core::convert::TryInto;
 // use crate::Value;
-use std::{ops::Mul, println};
-use ark_serialize::CanonicalSerialize;
+use crate::error::Poseidon2Error;
 
-const RATE: usize = 3;
+pub(crate) const RATE: usize = 3;
 
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub struct PoseidonAlgorithm {}
 
 impl Hasher for PoseidonAlgorithm {
@@ -457,6 +456,13 @@ lazy_static! {
     };
 }
 
+// Permutation output followed by the intermediate states kept for witness generation:
+// (output, state1, state2, state3, state4, input, t0).
+pub type PermutationTrace = (Vec<Fr>, [Fr;4], [Fr;4], [Fr;4], [Fr;4], [Fr;4], Fr);
+
+// Sponge state after a duplex followed by the permutation trace and the state before absorbing.
+type DuplexTrace = ([Fr;4], [Fr;4], [Fr;4], [Fr;4], [Fr;4], [Fr;4], [Fr;4], Fr);
+
 pub struct Poseidon2 {
     cache: [Fr; 3],
     state: [Fr; 4],
@@ -469,14 +475,21 @@ pub struct Poseidon2 {
 pub fn poseidon2_permutation(
     inputs: &[Fr],
     len: u32,
-// ) -> Result<Vec<Fr>, BlackBoxResolutionError> {
-) -> (Vec<Fr>, [Fr;4], [Fr;4], [Fr;4], [Fr;4], [Fr;4], Fr) {
-    // println!("Entered here1");
-
+) -> Result<PermutationTrace, Poseidon2Error> {
     let poseidon = Poseidon2Rs::new();
     poseidon.permutation(inputs, len)
 }
 
+// Same as poseidon2_permutation with custom parameters, which are validated first.
+pub fn poseidon2_permutation_with_config(
+    inputs: &[Fr],
+    len: u32,
+    config: &Poseidon2Config,
+) -> Result<PermutationTrace, Poseidon2Error> {
+    let poseidon = Poseidon2Rs::with_config(config)?;
+    poseidon.permutation(inputs, len)
+}
+
 pub(crate) struct Poseidon2Rs<'a> {
     config: &'a Poseidon2Config,
 }
@@ -489,11 +502,38 @@ pub struct Poseidon2Config {
     pub round_constant: [[Fr; 4]; 64],
 }
 
+impl Poseidon2Config {
+    // The permutation is implemented for a state of width 4 and reads one row of round_constant per round.
+    pub fn validate(&self) -> Result<(), Poseidon2Error> {
+        if self.t != 4 {
+            return Err(Poseidon2Error::InvalidConfig(format!("unsupported state width t = {}, expected 4", self.t)));
+        }
+        if !self.rounds_f.is_multiple_of(2) {
+            return Err(Poseidon2Error::InvalidConfig(format!("rounds_f = {} must be even", self.rounds_f)));
+        }
+        let rounds = self.rounds_f as usize + self.rounds_p as usize;
+        if rounds > self.round_constant.len() {
+            return Err(Poseidon2Error::InvalidConfig(format!(
+                "{} rounds but only {} rows of round constants",
+                rounds,
+                self.round_constant.len()
+            )));
+        }
+        Ok(())
+    }
+}
+
 impl<'a> Poseidon2Rs<'a> {
+    // Uses the built-in BN254 parameters (POSEIDON2_CONFIG), which are valid.
     pub(crate) fn new() -> Self {
         Poseidon2Rs { config: &POSEIDON2_CONFIG }
     }
 
+    pub(crate) fn with_config(config: &'a Poseidon2Config) -> Result<Self, Poseidon2Error> {
+        config.validate()?;
+        Ok(Poseidon2Rs { config })
+    }
+
     fn single_box(x: Fr) -> Fr {
         let s = x * x;
         s * s * x
@@ -543,7 +583,7 @@ impl<'a> Poseidon2Rs<'a> {
             sum += *i;
         }
         for (index, i) in input.iter_mut().enumerate() {
-            *i = *i * self.config.internal_matrix_diagonal[index];
+            *i *= self.config.internal_matrix_diagonal[index];
             *i += sum;
         }
     }
@@ -552,30 +592,13 @@ impl<'a> Poseidon2Rs<'a> {
         &self,
         inputs: &[Fr],
         len: u32,
-    // ) -> Result<Vec<Fr>, BlackBoxResolutionError> {
-    ) -> (Vec<Fr>, [Fr;4], [Fr;4], [Fr;4], [Fr;4], [Fr;4], Fr) {
+    ) -> Result<PermutationTrace, Poseidon2Error> {
         if len as usize != inputs.len() {
-            println!("(OG BlackBoxResolutionError) the number of inputs does not match specified length.");
+            return Err(Poseidon2Error::InputLengthMismatch { expected: len as usize, found: inputs.len() });
         }
-        // if len as usize != inputs.len() {
-        //     return Err(BlackBoxResolutionError::Failed(
-        //         acir::BlackBoxFunc::Poseidon2Permutation,
-        //         format!(
-        //             "the number of inputs does not match specified length. {} > {}",
-        //             inputs.len(),
-        //             len
-        //         ),
-        //     ));
-        // }
         if len != self.config.t {
-            println!("(OG BlackBoxResolutionError) Expected x values but encountered y");
+            return Err(Poseidon2Error::InvalidStateWidth { expected: self.config.t, found: len });
         }
-        // if len != self.config.t {
-        //     return Err(BlackBoxResolutionError::Failed(
-        //         acir::BlackBoxFunc::Poseidon2Permutation,
-        //         format!("Expected {} values but encountered {}", self.config.t, len),
-        //     ));
-        // }
         // Read witness assignments
         let mut state = [Fr::zero(); 4];
         for (index, input) in inputs.iter().enumerate() {
@@ -625,7 +648,7 @@ impl<'a> Poseidon2Rs<'a> {
 
         let result = state.into();    // NOTE: Presumably returning Vec because array size unknown
         // println!("state after into {:?}", result);   // STAYS SAME
-        (result, state1, state2, state3, state4, input, t0)
+        Ok((result, state1, state2, state3, state4, input, t0))
     }
 }
 
@@ -643,7 +666,7 @@ impl Poseidon2 {
         result
     }
 
-    fn perform_duplex(&mut self) -> ([Fr;4], [Fr;4], [Fr;4], [Fr;4], [Fr;4], [Fr;4], [Fr;4], Fr){
+    fn perform_duplex(&mut self) -> DuplexTrace {
         // println!("BEGINNING DUPLEX STATE");
         let begin = self.state;
         // add the cache into sponge state
@@ -654,11 +677,9 @@ impl Poseidon2 {
                 self.state[i] += self.cache[i];
             }
         }
-        let mut state_array: [Fr; 4] = [Fr::zero(); 4];
-        for j in 0..4 {
-            state_array[j] = self.state[j];
-        }
-        let vec_output = poseidon2_permutation(&state_array, 4);
+        let state_array: [Fr; 4] = self.state;
+        // The sponge state always has width t = 4, which is what the built-in config expects.
+        let vec_output = poseidon2_permutation(&state_array, 4).expect("sponge state must match the permutation width");
         // let length: usize = vec_output.len();
         // let array_output = [Fr::zero(); length];
         // for k in 0..length {
@@ -701,7 +722,7 @@ impl Poseidon2 {
 
         // assert(!self.squeeze_mode);
         // If we're in absorb mode, apply sponge permutation to compress the cache.
-        let result = self.perform_duplex();
+        self.perform_duplex();
         self.squeeze_mode = true;
 
         // Pop one item off the top of the permutation and return it.
@@ -729,10 +750,10 @@ impl Poseidon2 {
         
         // let return_sponge = sponge;
         // println!("input.len() {:?}", input.len());
-        for i in 0..input.len() {
+        for (i, element) in input.iter().enumerate() {
             if i < in_len as usize {    // NOTE: usize is 64-bit so always u32->usize not the other way around
                 // println!("absorb {:?}", i);
-                sponge.absorb(input[i]); 
+                sponge.absorb(*element);
             }
         }
 
@@ -751,16 +772,69 @@ impl Poseidon2 {
     }
 }
 
+#[derive(Default)]
 pub struct Poseidon2Hasher {
-    _state: Vec<Fr>,
-}
-
-impl Default for Poseidon2Hasher {
-    fn default() -> Self {
-        Poseidon2Hasher { _state: Vec::default() }  // Empty vector
-    }
+    _state: Vec<Fr>,    // Empty vector by default
 }
 
 pub fn hasher(leaves: [Fr; 2]) -> Fr {
     Poseidon2::hash([leaves[0], leaves[1]], 2)
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_permutation_known_answer() {
+        // Test vector from the Noir/Barretenberg Poseidon2 implementation.
+        let inputs = [Fr::from(0u64), Fr::from(1u64), Fr::from(2u64), Fr::from(3u64)];
+        let output = poseidon2_permutation(&inputs, 4).unwrap().0;
+        assert_eq!(output[0], field_from_hex("01bd538c2ee014ed5141b29e9ae240bf8db3fe5b9a38629a9647cf8d76c01737"));
+        assert_eq!(output[3], field_from_hex("2e11c5cff2a22c64d01304b778d78f6998eff1ab73163a35603f54794c30847a"));
+    }
+
+    #[test]
+    fn test_permutation_input_errors() {
+        let inputs = [Fr::from(1u64); 5];
+        assert_eq!(
+            poseidon2_permutation(&inputs[..3], 4).unwrap_err(),
+            Poseidon2Error::InputLengthMismatch { expected: 4, found: 3 }
+        );
+        assert_eq!(
+            poseidon2_permutation(&inputs, 5).unwrap_err(),
+            Poseidon2Error::InvalidStateWidth { expected: 4, found: 5 }
+        );
+        assert_eq!(
+            poseidon2_permutation(&inputs[..3], 3).unwrap_err(),
+            Poseidon2Error::InvalidStateWidth { expected: 4, found: 3 }
+        );
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        assert!(POSEIDON2_CONFIG.validate().is_ok());
+        let inputs = [Fr::from(0u64), Fr::from(1u64), Fr::from(2u64), Fr::from(3u64)];
+
+        let mut config = Poseidon2Config {
+            t: 4,
+            rounds_f: 8,
+            rounds_p: 57,
+            internal_matrix_diagonal: *INTERNAL_MATRIX_DIAGONAL,
+            round_constant: *ROUND_CONSTANT,
+        };
+        assert!(matches!(poseidon2_permutation_with_config(&inputs, 4, &config), Err(Poseidon2Error::InvalidConfig(_))));
+        config.rounds_p = 56;
+        config.rounds_f = 7;
+        assert!(matches!(poseidon2_permutation_with_config(&inputs, 4, &config), Err(Poseidon2Error::InvalidConfig(_))));
+        config.rounds_f = 8;
+        config.t = 3;
+        assert!(matches!(poseidon2_permutation_with_config(&inputs, 4, &config), Err(Poseidon2Error::InvalidConfig(_))));
+        config.t = 4;
+        assert_eq!(
+            poseidon2_permutation_with_config(&inputs, 4, &config).unwrap(),
+            poseidon2_permutation(&inputs, 4).unwrap()
+        );
+    }
+}
@@ -0,0 +1,242 @@
+use crate::poseidon::{Poseidon2Config, POSEIDON2_CONFIG, RATE};
+use ark_bn254::Fr;
+use ark_ff::One;
+use ark_r1cs_std::fields::fp::FpVar;
+use ark_r1cs_std::prelude::*;
+use ark_relations::r1cs::SynthesisError;
+
+/* R1CS version of poseidon::Poseidon2Rs and poseidon::Poseidon2 (t=4, rate 3).
+   Every step mirrors the native code so that the in-circuit output equals PoseidonAlgorithm::hash. */
+
+pub struct Poseidon2Gadget<'a> {
+    config: &'a Poseidon2Config,
+}
+
+impl Default for Poseidon2Gadget<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> Poseidon2Gadget<'a> {
+    pub fn new() -> Self {
+        Poseidon2Gadget { config: &POSEIDON2_CONFIG }
+    }
+
+    // x^5: 3 constraints
+    fn single_box(x: &FpVar<Fr>) -> Result<FpVar<Fr>, SynthesisError> {
+        let s = x.square()?;
+        Ok(s.square()? * x)
+    }
+
+    fn s_box(input: &mut [FpVar<Fr>; 4]) -> Result<(), SynthesisError> {
+        for i in input.iter_mut() {
+            *i = Self::single_box(i)?;
+        }
+        Ok(())
+    }
+
+    fn add_round_constants(&self, state: &mut [FpVar<Fr>; 4], round: usize) {
+        for (state_element, constant_element) in state.iter_mut().zip(self.config.round_constant[round]) {
+            *state_element += constant_element;
+        }
+    }
+
+    // Same addition chain as Poseidon2Rs::matrix_multiplication_4x4. Linear, so it costs no constraints.
+    fn matrix_multiplication_4x4(input: &mut [FpVar<Fr>; 4]) {
+        let t0 = &input[0] + &input[1]; // A + B
+        let t1 = &input[2] + &input[3]; // C + D
+        let t2 = &input[1] + &input[1] + &t1; // 2B + C + D
+        let t3 = &input[3] + &input[3] + &t0; // 2D + A + B
+        let t4 = &t1 + &t1;
+        let t4 = &t4 + &t4 + &t3; // A + B + 4C + 6D
+        let t5 = &t0 + &t0;
+        let t5 = &t5 + &t5 + &t2; // 4A + 6B + C + D
+        let t6 = &t3 + &t5; // 5A + 7B + C + 3D
+        let t7 = &t2 + &t4; // A + 3B + 5C + 7D
+        *input = [t6, t5, t7, t4];
+    }
+
+    fn internal_m_multiplication(&self, input: &mut [FpVar<Fr>; 4]) {
+        let sum: FpVar<Fr> = input.iter().sum();
+        for (index, i) in input.iter_mut().enumerate() {
+            *i = &*i * self.config.internal_matrix_diagonal[index] + &sum;
+        }
+    }
+
+    pub fn permutation(&self, inputs: &[FpVar<Fr>; 4]) -> Result<[FpVar<Fr>; 4], SynthesisError> {
+        let mut state = inputs.clone();
+
+        // Apply 1st linear layer
+        Self::matrix_multiplication_4x4(&mut state);
+
+        // First set of external rounds
+        let rf_first = self.config.rounds_f / 2;
+        for r in 0..rf_first {
+            self.add_round_constants(&mut state, r as usize);
+            Self::s_box(&mut state)?;
+            Self::matrix_multiplication_4x4(&mut state);
+        }
+
+        // Internal rounds
+        let p_end = rf_first + self.config.rounds_p;
+        for r in rf_first..p_end {
+            state[0] += self.config.round_constant[r as usize][0];
+            state[0] = Self::single_box(&state[0])?;
+            self.internal_m_multiplication(&mut state);
+        }
+
+        // Remaining external rounds
+        let num_rounds = self.config.rounds_f + self.config.rounds_p;
+        for i in p_end..num_rounds {
+            self.add_round_constants(&mut state, i as usize);
+            Self::s_box(&mut state)?;
+            Self::matrix_multiplication_4x4(&mut state);
+        }
+
+        Ok(state)
+    }
+
+    // Fixed-length hash, the in-circuit counterpart of PoseidonAlgorithm::hash.
+    pub fn hash<const N: usize>(&self, input: &[FpVar<Fr>; N]) -> Result<FpVar<Fr>, SynthesisError> {
+        self.hash_internal(input, N as u32, false)
+    }
+
+    // Mirrors Poseidon2::hash_internal: only the first `in_len` inputs are absorbed.
+    pub fn hash_internal(
+        &self,
+        input: &[FpVar<Fr>],
+        in_len: u32,
+        is_variable_length: bool,
+    ) -> Result<FpVar<Fr>, SynthesisError> {
+        let two_pow_64 = Fr::from(18446744073709551616_u128);
+        let iv = two_pow_64 * Fr::from(in_len);
+
+        let mut sponge = Poseidon2SpongeVar::new(self, iv);
+        for element in input.iter().take(in_len as usize) {
+            sponge.absorb(element)?;
+        }
+
+        // In the case where the hash preimage is variable-length, we append `1` to the end of the input (see Poseidon2::hash_internal)
+        if is_variable_length {
+            sponge.absorb(&FpVar::constant(Fr::one()))?;
+        }
+
+        sponge.squeeze()
+    }
+}
+
+// In-circuit counterpart of poseidon::Poseidon2 (the duplex sponge).
+struct Poseidon2SpongeVar<'a, 'b> {
+    gadget: &'b Poseidon2Gadget<'a>,
+    cache: Vec<FpVar<Fr>>,
+    state: [FpVar<Fr>; 4],
+}
+
+impl<'a, 'b> Poseidon2SpongeVar<'a, 'b> {
+    fn new(gadget: &'b Poseidon2Gadget<'a>, iv: Fr) -> Self {
+        let mut state = [FpVar::zero(), FpVar::zero(), FpVar::zero(), FpVar::zero()];
+        state[RATE] = FpVar::constant(iv);
+        Poseidon2SpongeVar { gadget, cache: Vec::with_capacity(RATE), state }
+    }
+
+    fn perform_duplex(&mut self) -> Result<(), SynthesisError> {
+        // add the cache into sponge state (zero-padded)
+        for (state_element, cached) in self.state.iter_mut().zip(self.cache.iter()) {
+            *state_element += cached;
+        }
+        self.state = self.gadget.permutation(&self.state)?;
+        Ok(())
+    }
+
+    fn absorb(&mut self, input: &FpVar<Fr>) -> Result<(), SynthesisError> {
+        if self.cache.len() == RATE {
+            // If we're absorbing, and the cache is full, apply the sponge permutation to compress the cache
+            self.perform_duplex()?;
+            self.cache.clear();
+        }
+        self.cache.push(input.clone());
+        Ok(())
+    }
+
+    fn squeeze(&mut self) -> Result<FpVar<Fr>, SynthesisError> {
+        self.perform_duplex()?;
+        Ok(self.state[0].clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hasher::Hasher;
+    use crate::poseidon::{poseidon2_permutation, Poseidon2, PoseidonAlgorithm};
+    use ark_r1cs_std::R1CSVar;
+    use ark_relations::r1cs::ConstraintSystem;
+    use ark_std::{test_rng, UniformRand};
+
+    fn witnesses<const N: usize>(cs: &ark_relations::r1cs::ConstraintSystemRef<Fr>, values: &[Fr; N]) -> [FpVar<Fr>; N] {
+        values.map(|value| FpVar::new_witness(cs.clone(), || Ok(value)).unwrap())
+    }
+
+    #[test]
+    fn test_permutation_gadget() {
+        let mut rng = test_rng();
+        let input = [Fr::rand(&mut rng), Fr::rand(&mut rng), Fr::rand(&mut rng), Fr::rand(&mut rng)];
+        let expected = poseidon2_permutation(&input, 4).unwrap().0;
+
+        let cs = ConstraintSystem::<Fr>::new_ref();
+        let input_var = witnesses(&cs, &input);
+        let output = Poseidon2Gadget::new().permutation(&input_var).unwrap();
+
+        assert_eq!(output.value().unwrap().to_vec(), expected);
+        assert!(cs.is_satisfied().unwrap());
+    }
+
+    fn check_hash<const N: usize>(rng: &mut impl ark_std::rand::Rng) {
+        let input: [Fr; N] = core::array::from_fn(|_| Fr::rand(rng));
+
+        let cs = ConstraintSystem::<Fr>::new_ref();
+        let input_var = witnesses(&cs, &input);
+        let output = Poseidon2Gadget::new().hash(&input_var).unwrap();
+
+        assert_eq!(output.value().unwrap(), PoseidonAlgorithm::hash(input));
+        assert!(cs.is_satisfied().unwrap());
+    }
+
+    #[test]
+    fn test_hash_gadget_matches_native() {
+        let mut rng = test_rng();
+        check_hash::<1>(&mut rng);
+        check_hash::<2>(&mut rng);
+        check_hash::<3>(&mut rng);
+        check_hash::<4>(&mut rng);
+        check_hash::<7>(&mut rng);
+    }
+
+    #[test]
+    fn test_variable_length_hash_gadget() {
+        let mut rng = test_rng();
+        let input = [Fr::rand(&mut rng), Fr::rand(&mut rng), Fr::rand(&mut rng), Fr::rand(&mut rng)];
+
+        let cs = ConstraintSystem::<Fr>::new_ref();
+        let input_var = witnesses(&cs, &input);
+        let output = Poseidon2Gadget::new().hash_internal(&input_var, 3, true).unwrap();
+
+        assert_eq!(output.value().unwrap(), Poseidon2::hash(input, 3));
+        assert!(cs.is_satisfied().unwrap());
+    }
+
+    #[test]
+    fn test_wrong_output_unsatisfied() {
+        let mut rng = test_rng();
+        let input = [Fr::rand(&mut rng), Fr::rand(&mut rng)];
+
+        let cs = ConstraintSystem::<Fr>::new_ref();
+        let input_var = witnesses(&cs, &input);
+        let output = Poseidon2Gadget::new().hash(&input_var).unwrap();
+        let claimed = FpVar::new_input(cs.clone(), || Ok(PoseidonAlgorithm::hash([input[1], input[0]]))).unwrap();
+        output.enforce_equal(&claimed).unwrap();
+
+        assert!(!cs.is_satisfied().unwrap());
+    }
+}
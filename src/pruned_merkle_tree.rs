@@ -0,0 +1,284 @@
+use crate::error::MerkleTreeError;
+use crate::hasher::Hasher;
+use crate::incremental_merkle_tree::{check_depth, empty_hashes, MerkleTree, Opening};
+use crate::merkle_proof::MerkleProof;
+use crate::poseidon::PoseidonAlgorithm;
+use std::collections::{BTreeMap, BTreeSet};
+
+// Append-only Merkle tree that only keeps the nodes needed to append leaves and to open the marked leaves:
+// the paths of the last leaf and of every marked leaf, with their siblings. Any other subtree is only kept as
+// its root (the sibling of one of these paths), so memory is O(depth * (marked leaves + 1)) whatever the number
+// of leaves. Pruning does not change the root: from_tree keeps the root of the MerkleTree it starts from.
+#[derive(Debug, Clone)]
+pub struct PrunedMerkleTree<H = PoseidonAlgorithm>
+where
+    H: Hasher,
+{
+    depth: u32,
+    // Kept nodes below the root by (level, index). Missing nodes after the last leaf are empty.
+    nodes: BTreeMap<(usize, usize), H::Hash>,
+    root: H::Hash,
+    marked: BTreeSet<usize>,
+    empty_hashes: Vec<H::Hash>,
+    leaves_count: usize,
+}
+
+impl<H> PrunedMerkleTree<H>
+where
+    H: Hasher,
+{
+    // No leaves and nothing marked yet.
+    pub fn new(depth: u32) -> Result<Self, MerkleTreeError> {
+        check_depth(depth)?;
+        let empty_hashes = empty_hashes::<H>(depth);
+        Ok(Self {
+            depth,
+            nodes: BTreeMap::new(),
+            root: empty_hashes[empty_hashes.len() - 1],
+            marked: BTreeSet::new(),
+            empty_hashes,
+            leaves_count: 0,
+        })
+    }
+
+    // Prunes a full tree down to the nodes needed for the marked leaves.
+    pub fn from_tree(tree: &MerkleTree<H>, marked: &[usize]) -> Result<Self, MerkleTreeError> {
+        let mut pruned = Self::new(tree.depth() as u32)?;
+        pruned.root = *tree.root()?;
+        pruned.leaves_count = tree.leaves_count();
+        for &leaf_index in marked.iter() {
+            if leaf_index >= tree.leaves_count() {
+                return Err(MerkleTreeError::LeafNotFound { index: leaf_index, leaves_count: tree.leaves_count() });
+            }
+            pruned.marked.insert(leaf_index);
+        }
+
+        let last_leaf = tree.leaves_count().checked_sub(1);
+        for leaf_index in pruned.marked.iter().copied().chain(last_leaf) {
+            let mut index = leaf_index;
+            for level in 0..pruned.levels() {
+                pruned.nodes.insert((level, index), tree.tree[level][index]);
+                pruned.nodes.insert((level, index ^ 1), tree.tree[level][index ^ 1]);
+                index /= 2;
+            }
+        }
+        Ok(pruned)
+    }
+
+    // Inserts the leaf in the first unused slot and returns its index. The path of the previous leaf is
+    // discarded, except for the nodes still needed by marked leaves.
+    pub fn insert_leaf(&mut self, leaf: H::Hash) -> Result<usize, MerkleTreeError> {
+        if self.leaves_count >= self.capacity() {
+            return Err(MerkleTreeError::TreeFull { capacity: self.capacity() });
+        }
+
+        let leaf_index = self.leaves_count;
+        let mut index = leaf_index;
+        let mut current = leaf;
+        for level in 0..self.levels() {
+            self.nodes.insert((level, index), current);
+            current = if index.is_multiple_of(2) {
+                H::concat_and_hash(&current, &self.empty_hashes[level])
+            } else {
+                // The left sibling is on the path of the previous leaf, which is always kept
+                H::concat_and_hash(&self.nodes[&(level, index - 1)], &current)
+            };
+            index /= 2;
+        }
+
+        self.root = current;
+        self.leaves_count += 1;
+        if leaf_index > 0 {
+            self.prune_path(leaf_index - 1);
+        }
+        Ok(leaf_index)
+    }
+
+    // Keeps the path of an inserted leaf so that it can be opened later. Only possible while its nodes are still
+    // known, i.e. for the last leaf (mark it right after inserting it) or leaves of from_tree.
+    pub fn mark(&mut self, leaf_index: usize) -> Result<(), MerkleTreeError> {
+        self.proof(leaf_index)?;
+        self.marked.insert(leaf_index);
+        Ok(())
+    }
+
+    // Stops keeping the path of a leaf and discards the nodes nobody else needs. Returns whether it was marked.
+    pub fn unmark(&mut self, leaf_index: usize) -> bool {
+        let was_marked = self.marked.remove(&leaf_index);
+        if was_marked {
+            self.prune_path(leaf_index);
+        }
+        was_marked
+    }
+
+    pub fn is_marked(&self, leaf_index: usize) -> bool {
+        self.marked.contains(&leaf_index)
+    }
+
+    // Indices of the marked leaves, in increasing order.
+    pub fn marked_leaves(&self) -> impl Iterator<Item = usize> + '_ {
+        self.marked.iter().copied()
+    }
+
+    pub fn root(&self) -> &H::Hash {
+        &self.root
+    }
+
+    pub fn leaves_count(&self) -> usize {
+        self.leaves_count
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth as usize
+    }
+
+    // Leaf slots, whether their nodes are kept or pruned.
+    pub fn capacity(&self) -> usize {
+        1usize << (self.depth - 1)
+    }
+
+    // Number of nodes kept in memory (besides the root).
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    // Opening of a marked leaf (or of the last leaf) in the format of MerkleTree::opening.
+    pub fn opening(&self, leaf_index: u32) -> Result<Opening<H>, MerkleTreeError> {
+        Ok(self.proof(leaf_index as usize)?.to_opening())
+    }
+
+    // Opening of a marked leaf (or of the last leaf) as a MerkleProof.
+    pub fn proof(&self, leaf_index: usize) -> Result<MerkleProof<H>, MerkleTreeError> {
+        if leaf_index >= self.capacity() {
+            return Err(MerkleTreeError::IndexOutOfRange { index: leaf_index, capacity: self.capacity() });
+        }
+        if leaf_index >= self.leaves_count {
+            return Err(MerkleTreeError::LeafNotFound { index: leaf_index, leaves_count: self.leaves_count });
+        }
+
+        let pruned = MerkleTreeError::LeafPruned(leaf_index);
+        let leaf = self.node(0, leaf_index).ok_or(pruned.clone())?;
+        let mut siblings = Vec::with_capacity(self.levels());
+        let mut index = leaf_index;
+        for level in 0..self.levels() {
+            siblings.push(self.node(level, index ^ 1).ok_or(pruned.clone())?);
+            index /= 2;
+        }
+        Ok(MerkleProof::new(leaf_index, leaf, siblings))
+    }
+
+    // Levels below the root.
+    fn levels(&self) -> usize {
+        self.depth as usize - 1
+    }
+
+    // The node at (level, index), unless it was discarded.
+    fn node(&self, level: usize, index: usize) -> Option<H::Hash> {
+        if level == self.levels() {
+            return Some(self.root);
+        }
+        match self.nodes.get(&(level, index)) {
+            Some(node) => Some(*node),
+            // Nothing was inserted below it yet
+            None if index << level >= self.leaves_count => Some(self.empty_hashes[level]),
+            None => None,
+        }
+    }
+
+    // Whether (level, index) is on the path of the last leaf or of a marked leaf, or is a sibling of such a node.
+    fn is_needed(&self, level: usize, index: usize) -> bool {
+        // Leaves below the parent of the node
+        let first_leaf = (index / 2) << (level + 1);
+        let last_leaf = first_leaf + (1usize << (level + 1)) - 1;
+        let covers_last_leaf = self.leaves_count > 0 && (first_leaf..=last_leaf).contains(&(self.leaves_count - 1));
+        covers_last_leaf || self.marked.range(first_leaf..=last_leaf).next().is_some()
+    }
+
+    // Discards the nodes on the path of the leaf (and their siblings) that are not needed anymore.
+    fn prune_path(&mut self, leaf_index: usize) {
+        let mut index = leaf_index;
+        for level in 0..self.levels() {
+            for node in [index, index ^ 1] {
+                if !self.is_needed(level, node) {
+                    self.nodes.remove(&(level, node));
+                }
+            }
+            index /= 2;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ark_bn254::Fr;
+
+    #[test]
+    fn test_pruned_tree_matches_merkle_tree() {
+        let mut merkle_tree = MerkleTree::<PoseidonAlgorithm>::build_empty_tree(8).unwrap();
+        let mut pruned = PrunedMerkleTree::<PoseidonAlgorithm>::new(8).unwrap();
+        assert_eq!(merkle_tree.root(), Ok(pruned.root()));
+
+        for leaf in 0..64u64 {
+            assert_eq!(pruned.insert_leaf(Fr::from(leaf)), merkle_tree.insert_leaf(Fr::from(leaf)));
+            assert_eq!(merkle_tree.root(), Ok(pruned.root()));
+            if leaf % 9 == 4 {
+                pruned.mark(leaf as usize).unwrap();
+            }
+            for leaf_index in pruned.marked_leaves().chain([leaf as usize]) {
+                assert_eq!(pruned.opening(leaf_index as u32), merkle_tree.opening(leaf_index as u32));
+            }
+            // Path and siblings of the last leaf and of each marked leaf
+            assert!(pruned.node_count() <= 2 * 7 * (pruned.marked_leaves().count() + 1));
+        }
+        assert_eq!(pruned.marked_leaves().collect::<Vec<_>>(), vec![4, 13, 22, 31, 40, 49, 58]);
+
+        // Unmarked leaves cannot be opened anymore
+        assert_eq!(pruned.opening(6), Err(MerkleTreeError::LeafPruned(6)));
+        assert_eq!(pruned.mark(6), Err(MerkleTreeError::LeafPruned(6)));
+        assert_eq!(pruned.opening(64), Err(MerkleTreeError::LeafNotFound { index: 64, leaves_count: 64 }));
+        assert_eq!(pruned.opening(128), Err(MerkleTreeError::IndexOutOfRange { index: 128, capacity: 128 }));
+
+        let node_count = pruned.node_count();
+        assert!(pruned.unmark(22));
+        assert!(!pruned.unmark(22));
+        assert!(pruned.node_count() < node_count);
+        assert_eq!(pruned.opening(22), Err(MerkleTreeError::LeafPruned(22)));
+        assert_eq!(pruned.opening(13), merkle_tree.opening(13));
+    }
+
+    #[test]
+    fn test_from_tree() {
+        let mut merkle_tree = MerkleTree::<PoseidonAlgorithm>::build_empty_tree(6).unwrap();
+        merkle_tree.insert_leaves(&(0..21u64).map(Fr::from).collect::<Vec<_>>()).unwrap();
+        let mut pruned = PrunedMerkleTree::from_tree(&merkle_tree, &[0, 7, 12]).unwrap();
+        assert!(pruned.is_marked(7));
+        assert_eq!(pruned.leaves_count(), 21);
+
+        for leaf in 21..32u64 {
+            merkle_tree.insert_leaf(Fr::from(leaf)).unwrap();
+            pruned.insert_leaf(Fr::from(leaf)).unwrap();
+            assert_eq!(merkle_tree.root(), Ok(pruned.root()));
+            for leaf_index in [0u32, 7, 12, leaf as u32] {
+                assert_eq!(pruned.opening(leaf_index), merkle_tree.opening(leaf_index));
+            }
+        }
+        assert_eq!(pruned.opening(20), Err(MerkleTreeError::LeafPruned(20)));
+
+        assert_eq!(
+            PrunedMerkleTree::from_tree(&merkle_tree, &[32]).unwrap_err(),
+            MerkleTreeError::LeafNotFound { index: 32, leaves_count: 32 }
+        );
+        let mut full = PrunedMerkleTree::<PoseidonAlgorithm>::from_tree(&merkle_tree, &[]).unwrap();
+        assert_eq!(full.insert_leaf(Fr::from(0u64)), Err(MerkleTreeError::TreeFull { capacity: 32 }));
+    }
+
+    #[test]
+    fn test_depth_one_tree() {
+        let mut pruned = PrunedMerkleTree::<PoseidonAlgorithm>::new(1).unwrap();
+        pruned.insert_leaf(Fr::from(3u64)).unwrap();
+        pruned.mark(0).unwrap();
+        assert_eq!(pruned.proof(0).unwrap().leaf, Fr::from(3u64));
+        assert_eq!(pruned.opening(0), Ok(vec![]));
+    }
+}
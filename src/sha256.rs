@@ -0,0 +1,70 @@
+use crate::hasher::Hasher;
+use sha2::{Digest, Sha256};
+
+pub type Sha256Hash = [u8; 32];
+
+// Domain separation prefixes from RFC 6962 (Certificate Transparency).
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0x01;
+
+#[derive(Clone, Debug)]
+pub struct Sha256Hasher {}
+
+impl Sha256Hasher {
+    // Hashes raw leaf data as SHA-256(0x00 || data). Use this to turn log entries into leaves before insert_leaf.
+    pub fn hash_leaf_data(data: &[u8]) -> Sha256Hash {
+        let mut hasher = Sha256::new();
+        hasher.update([LEAF_PREFIX]);
+        hasher.update(data);
+        hasher.finalize().into()
+    }
+}
+
+impl Hasher for Sha256Hasher {
+    type Hash = Sha256Hash;
+
+    // Plain SHA-256 over the concatenated digests (no prefix).
+    fn hash<const N: usize>(data: [Sha256Hash; N]) -> Sha256Hash {
+        let mut hasher = Sha256::new();
+        for digest in data.iter() {
+            hasher.update(digest);
+        }
+        hasher.finalize().into()
+    }
+
+    fn leaf_hash(leaf: Sha256Hash) -> Sha256Hash {
+        Self::hash_leaf_data(&leaf)
+    }
+
+    // SHA-256(0x01 || left || right)
+    fn concat_and_hash(left: &Sha256Hash, right: &Sha256Hash) -> Sha256Hash {
+        let mut hasher = Sha256::new();
+        hasher.update([NODE_PREFIX]);
+        hasher.update(left);
+        hasher.update(right);
+        hasher.finalize().into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rfc6962_prefixes() {
+        let leaf = Sha256Hasher::hash_leaf_data(b"hello");
+        let expected_leaf: Sha256Hash = Sha256::digest([&[0x00u8][..], b"hello"].concat()).into();
+        assert_eq!(leaf, expected_leaf);
+
+        let other = Sha256Hasher::hash_leaf_data(b"world");
+        let node = Sha256Hasher::concat_and_hash(&leaf, &other);
+        let expected_node: Sha256Hash = Sha256::digest([&[0x01u8][..], &leaf, &other].concat()).into();
+        assert_eq!(node, expected_node);
+
+        // A leaf whose data is the two children of a node does not hash to that node, nor to the unprefixed hash.
+        let payload = [&leaf[..], &other[..]].concat();
+        let leaf_of_payload = Sha256Hasher::hash_leaf_data(&payload);
+        assert_ne!(leaf_of_payload, node);
+        assert_ne!(leaf_of_payload, <[u8; 32]>::from(Sha256::digest(&payload)));
+    }
+}
@@ -0,0 +1,260 @@
+use crate::hasher::Hasher;
+use crate::incremental_merkle_tree::empty_hashes;
+use crate::poseidon::PoseidonAlgorithm;
+use ark_bn254::Fr;
+use ark_ff::{BigInt, BigInteger, PrimeField};
+use std::collections::BTreeMap;
+
+// Number of bits of a key, i.e. levels below the root: every Fr fits in 254 bits.
+pub const KEY_BITS: usize = 254;
+
+type NodeIndex = BigInt<4>;
+
+// Sparse Merkle tree committing to a key/value map: the leaf of key k is at index k (its bits, least significant
+// first, give the path from the leaf up) among 2^254 leaves. A leaf is H([key, value]) if the key is set and
+// the empty leaf (0) otherwise, so only the non-empty nodes are stored; the empty subtrees are given by empty_hashes
+// as in MerkleTree. A proof that the leaf of a key is empty is a proof that the key is not in the map.
+#[derive(Debug, Clone)]
+pub struct SparseMerkleTree<H = PoseidonAlgorithm>
+where
+    H: Hasher<Hash = Fr>,
+{
+    // Non-empty nodes below the root by (level, index).
+    nodes: BTreeMap<(usize, NodeIndex), Fr>,
+    values: BTreeMap<Fr, Fr>,
+    // One per level, the last one is the root of the empty tree.
+    empty_hashes: Vec<Fr>,
+    root: Fr,
+    _hasher: core::marker::PhantomData<H>,
+}
+
+// Proof that key is set to value (membership) or, if value is None, that key is not set (non-membership).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SparseMerkleProof {
+    pub key: Fr,
+    pub value: Option<Fr>,
+    // KEY_BITS siblings from the leaf up to (not including) the root.
+    pub siblings: Vec<Fr>,
+}
+
+impl<H> Default for SparseMerkleTree<H>
+where
+    H: Hasher<Hash = Fr>,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<H> SparseMerkleTree<H>
+where
+    H: Hasher<Hash = Fr>,
+{
+    pub fn new() -> Self {
+        let empty_hashes = empty_hashes::<H>(KEY_BITS as u32 + 1);
+        Self {
+            nodes: BTreeMap::new(),
+            values: BTreeMap::new(),
+            root: empty_hashes[KEY_BITS],
+            empty_hashes,
+            _hasher: core::marker::PhantomData,
+        }
+    }
+
+    // Sets the value of key and returns the previous one.
+    pub fn insert(&mut self, key: Fr, value: Fr) -> Option<Fr> {
+        self.update_path(key, leaf_hash::<H>(&key, &value));
+        self.values.insert(key, value)
+    }
+
+    // Unsets key and returns its value, if it was set.
+    pub fn delete(&mut self, key: &Fr) -> Option<Fr> {
+        let value = self.values.remove(key)?;
+        self.update_path(*key, self.empty_hashes[0]);
+        Some(value)
+    }
+
+    pub fn get(&self, key: &Fr) -> Option<&Fr> {
+        self.values.get(key)
+    }
+
+    pub fn root(&self) -> &Fr {
+        &self.root
+    }
+
+    // Number of keys that are set.
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    // Membership proof if the key is set, non-membership proof otherwise.
+    pub fn proof(&self, key: &Fr) -> SparseMerkleProof {
+        let mut index = key.into_bigint();
+        let mut siblings = Vec::with_capacity(KEY_BITS);
+        for level in 0..KEY_BITS {
+            siblings.push(self.node(level, &sibling(&index)));
+            index.div2();
+        }
+        SparseMerkleProof {
+            key: *key,
+            value: self.get(key).copied(),
+            siblings,
+        }
+    }
+
+    fn node(&self, level: usize, index: &NodeIndex) -> Fr {
+        self.nodes.get(&(level, *index)).copied().unwrap_or(self.empty_hashes[level])
+    }
+
+    // Writes the leaf of key and rehashes its path, storing only the nodes that are not empty.
+    fn update_path(&mut self, key: Fr, leaf: Fr) {
+        let mut index = key.into_bigint();
+        let mut current = leaf;
+        for level in 0..KEY_BITS {
+            if current == self.empty_hashes[level] {
+                self.nodes.remove(&(level, index));
+            } else {
+                self.nodes.insert((level, index), current);
+            }
+
+            let sibling_node = self.node(level, &sibling(&index));
+            current = if current == self.empty_hashes[level] && sibling_node == self.empty_hashes[level] {
+                // Both subtrees are empty
+                self.empty_hashes[level + 1]
+            } else if index.is_even() {
+                H::concat_and_hash(&current, &sibling_node)
+            } else {
+                H::concat_and_hash(&sibling_node, &current)
+            };
+            index.div2();
+        }
+        self.root = current;
+    }
+}
+
+impl SparseMerkleProof {
+    // Root of the tree in which the proof holds, computed with H. None if the proof does not have KEY_BITS siblings.
+    pub fn compute_root<H: Hasher<Hash = Fr>>(&self) -> Option<Fr> {
+        if self.siblings.len() != KEY_BITS {
+            return None;
+        }
+        let mut current = match self.value {
+            Some(value) => leaf_hash::<H>(&self.key, &value),
+            None => Fr::default(),
+        };
+        let index = self.key.into_bigint();
+        for (level, sibling) in self.siblings.iter().enumerate() {
+            if index.get_bit(level) {
+                current = H::concat_and_hash(sibling, &current);
+            } else {
+                current = H::concat_and_hash(&current, sibling);
+            }
+        }
+        Some(current)
+    }
+
+    // Checks the proof against the root of a SparseMerkleTree<H>.
+    pub fn verify<H: Hasher<Hash = Fr>>(&self, root: &Fr) -> bool {
+        self.compute_root::<H>() == Some(*root)
+    }
+}
+
+fn leaf_hash<H: Hasher<Hash = Fr>>(key: &Fr, value: &Fr) -> Fr {
+    H::hash([*key, *value])
+}
+
+fn sibling(index: &NodeIndex) -> NodeIndex {
+    let mut sibling = *index;
+    sibling.0[0] ^= 1;
+    sibling
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ark_ff::Zero;
+
+    type Proof = SparseMerkleProof;
+
+    #[test]
+    fn test_insert_get_delete() {
+        let mut tree = SparseMerkleTree::<PoseidonAlgorithm>::new();
+        let empty_root = *tree.root();
+        assert!(tree.is_empty());
+
+        let keys = [Fr::from(0u64), Fr::from(1u64), Fr::from(6u64), -Fr::from(1u64)];
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(tree.insert(*key, Fr::from(i as u64 + 10)), None);
+        }
+        assert_eq!(tree.len(), 4);
+        assert_eq!(tree.get(&Fr::from(6u64)), Some(&Fr::from(12u64)));
+        assert_eq!(tree.get(&Fr::from(7u64)), None);
+
+        // The root only depends on the contents, not on the order of the operations
+        let root = *tree.root();
+        assert_eq!(tree.insert(keys[1], Fr::from(100u64)), Some(Fr::from(11u64)));
+        assert_ne!(*tree.root(), root);
+        assert_eq!(tree.insert(keys[1], Fr::from(11u64)), Some(Fr::from(100u64)));
+        assert_eq!(*tree.root(), root);
+
+        let mut reordered = SparseMerkleTree::<PoseidonAlgorithm>::new();
+        for (i, key) in keys.iter().enumerate().rev() {
+            reordered.insert(*key, Fr::from(i as u64 + 10));
+        }
+        assert_eq!(*reordered.root(), root);
+
+        // A zero value is different from no value
+        tree.insert(Fr::from(3u64), Fr::zero());
+        assert_ne!(*tree.root(), root);
+        assert_eq!(tree.delete(&Fr::from(3u64)), Some(Fr::zero()));
+        assert_eq!(*tree.root(), root);
+        assert_eq!(tree.delete(&Fr::from(3u64)), None);
+
+        // Deleting everything gives back the empty tree, without empty nodes left in memory
+        for key in keys.iter() {
+            tree.delete(key).unwrap();
+        }
+        assert_eq!(*tree.root(), empty_root);
+        assert!(tree.nodes.is_empty());
+    }
+
+    #[test]
+    fn test_membership_and_non_membership_proofs() {
+        let mut tree = SparseMerkleTree::<PoseidonAlgorithm>::new();
+        for key in [2u64, 3, 1000] {
+            tree.insert(Fr::from(key), Fr::from(key * key));
+        }
+        let root = *tree.root();
+
+        let membership = tree.proof(&Fr::from(3u64));
+        assert_eq!(membership.value, Some(Fr::from(9u64)));
+        assert!(membership.verify::<PoseidonAlgorithm>(&root));
+
+        // The neighbour of a set key, and a key in an empty part of the tree
+        for key in [Fr::from(4u64), Fr::from(1001u64), -Fr::from(5u64)] {
+            let non_membership = tree.proof(&key);
+            assert_eq!(non_membership.value, None);
+            assert!(non_membership.verify::<PoseidonAlgorithm>(&root));
+        }
+
+        // Forged proofs
+        let wrong_value = Proof { value: Some(Fr::from(10u64)), ..membership.clone() };
+        assert!(!wrong_value.verify::<PoseidonAlgorithm>(&root));
+        let absent = Proof { value: None, ..membership.clone() };
+        assert!(!absent.verify::<PoseidonAlgorithm>(&root));
+        let wrong_key = Proof { key: Fr::from(2u64), ..membership.clone() };
+        assert!(!wrong_key.verify::<PoseidonAlgorithm>(&root));
+        let mut short = membership.clone();
+        short.siblings.pop();
+        assert!(!short.verify::<PoseidonAlgorithm>(&root));
+
+        // Proofs are against a given root
+        tree.delete(&Fr::from(3u64));
+        assert!(!membership.verify::<PoseidonAlgorithm>(tree.root()));
+        assert!(tree.proof(&Fr::from(3u64)).verify::<PoseidonAlgorithm>(tree.root()));
+    }
+}
@@ -0,0 +1,9 @@
+use crate::hasher::Hasher;
+use crate::incremental_merkle_tree::MerkleTree;
+
+// Fixture shared by the tests: inserts leaves_count leaves into the tree, the i-th one being leaf(i).
+pub fn build_mock_tree<H: Hasher>(merkle_tree: &mut MerkleTree<H>, leaves_count: usize, leaf: impl Fn(usize) -> H::Hash) {
+    for leaf_index in 0..leaves_count {
+        merkle_tree.insert_leaf(leaf(leaf_index)).unwrap();
+    }
+}